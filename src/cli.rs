@@ -0,0 +1,162 @@
+use std::error::Error;
+use structopt::StructOpt;
+use crate::config::{Config, ExportConfig, TlsConfig, UnixSocketConfig, DEFAULT_UNIX_SOCKET_MODE};
+use crate::support::registry::parse_size;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name="clouddrive", about="Serves cloud storage as NBD block devices.")]
+pub struct Opt{
+    /// Defaults to `serve`.
+    #[structopt(subcommand)]
+    pub command: Option<Command>
+}
+#[derive(Debug, StructOpt)]
+pub enum Command{
+    /// Serves the configured exports over NBD.
+    Serve(ServeOpt),
+    /// Lists the configured exports without connecting to their backends.
+    ListExports(ExportOpt),
+    /// Connects to an export and prints its size and capabilities.
+    Info{
+        export: String,
+        #[structopt(flatten)]
+        exports: ExportOpt
+    }
+}
+/// Where the exports come from. Without a configuration file a 1 GiB in-memory disk is served, along with
+/// the Seafile library SEAFILE_LIBRARY if set, unless an ad-hoc export is given.
+#[derive(Debug, StructOpt)]
+pub struct ExportOpt{
+    /// TOML configuration file.
+    #[structopt(short, long, env="CLOUDDRIVE_CONFIG")]
+    pub config: Option<String>,
+    /// Adds or replaces an export, e.g. `scratch=bytes+lru(cap=1024)+memory(size=2G)`.
+    #[structopt(long="export", number_of_values=1, parse(try_from_str=parse_export))]
+    pub exports: Vec<(String, String)>,
+    /// Ad-hoc in-memory export of the given size, e.g. `2G`.
+    #[structopt(long, parse(try_from_str=parse_size_arg), conflicts_with="provider")]
+    pub memory: Option<usize>,
+    /// Ad-hoc export built from a provider spec.
+    #[structopt(long)]
+    pub provider: Option<String>,
+    /// Name of the ad-hoc export.
+    #[structopt(long, default_value="scratch")]
+    pub name: String,
+    /// Size of the ad-hoc export, unless its provider spec gives one.
+    #[structopt(long, parse(try_from_str=parse_size_arg))]
+    pub size: Option<usize>,
+    /// Description of the ad-hoc export.
+    #[structopt(long)]
+    pub description: Option<String>,
+    /// Makes the ad-hoc export read-only.
+    #[structopt(long)]
+    pub read_only: bool
+}
+#[derive(Debug, StructOpt)]
+pub struct ServeOpt{
+    #[structopt(flatten)]
+    pub exports: ExportOpt,
+    /// TCP address to listen on, replacing those of the configuration file.
+    #[structopt(short, long, number_of_values=1, env="CLOUDDRIVE_ADDR")]
+    pub listen: Vec<String>,
+    /// Unix socket to listen on as well.
+    #[structopt(long, env="CLOUDDRIVE_UNIX_SOCKET")]
+    pub unix_socket: Option<String>,
+    /// Permissions of the Unix socket, in octal.
+    #[structopt(long, parse(try_from_str=parse_mode), env="CLOUDDRIVE_UNIX_SOCKET_MODE")]
+    pub unix_socket_mode: Option<u32>,
+    /// Requests served concurrently on each connection.
+    #[structopt(long, env="CLOUDDRIVE_MAX_IN_FLIGHT")]
+    pub max_in_flight: Option<usize>,
+    /// PEM certificate chain for NBD_OPT_STARTTLS.
+    #[structopt(long, requires="tls-key", env="CLOUDDRIVE_TLS_CERT")]
+    pub tls_cert: Option<String>,
+    /// PEM private key for NBD_OPT_STARTTLS.
+    #[structopt(long, requires="tls-cert", env="CLOUDDRIVE_TLS_KEY")]
+    pub tls_key: Option<String>,
+    /// Only accept clients with a certificate signed by this PEM CA.
+    #[structopt(long, env="CLOUDDRIVE_TLS_CLIENT_CA")]
+    pub tls_client_ca: Option<String>,
+    /// Refuse clients that do not upgrade to TLS. Also enabled by CLOUDDRIVE_TLS_REQUIRED=1.
+    #[structopt(long)]
+    pub tls_required: bool
+}
+
+fn parse_size_arg(value: &str)->Result<usize, String>{
+    parse_size(value).ok_or_else(|| format!("bad size: {}", value))
+}
+fn parse_mode(value: &str)->Result<u32, String>{
+    u32::from_str_radix(value, 8).map_err(|_| format!("bad mode: {}", value))
+}
+/// Whether a switch is turned on in the environment. Flags cannot take clap's `env`, which makes them expect a value.
+fn env_flag(name: &str)->bool{
+    std::env::var(name).map(|value| value=="1" || value.eq_ignore_ascii_case("true")).unwrap_or(false)
+}
+fn parse_export(value: &str)->Result<(String, String), String>{
+    match value.find('='){
+        Some(index) if index>0=>Ok((String::from(&value[..index]), String::from(&value[index+1..]))),
+        _=>Err(format!("expected NAME=SPEC: {}", value))
+    }
+}
+
+impl ExportOpt{
+    /// Loads the configuration file, if any, and applies the exports given on the command line.
+    pub fn load_config(&self)->Result<Config, Box<dyn Error>>{
+        let adhoc_provider=match (self.memory, &self.provider){
+            (Some(size), _)=>Some(format!("bytes+lru(cap=1024)+memory(size={})", size)),
+            (None, Some(spec))=>Some(spec.clone()),
+            (None, None)=>None
+        };
+        let mut config=match &self.config{
+            Some(path)=>Config::load(path)?,
+            None if adhoc_provider.is_some() || !self.exports.is_empty()=>Config{exports: Vec::new(), ..Config::default()},
+            None=>Config::default()
+        };
+        let mut exports: Vec<ExportConfig>=self.exports.iter().map(|(name, spec)| ExportConfig{
+            name: name.clone(),
+            description: None,
+            size: None,
+            read_only: false,
+            backend: None,
+            layers: Vec::new(),
+            provider: Some(spec.clone())
+        }).collect();
+        if let Some(spec)=adhoc_provider{
+            exports.push(ExportConfig{
+                name: self.name.clone(),
+                description: self.description.clone(),
+                size: self.size,
+                read_only: self.read_only,
+                backend: None,
+                layers: Vec::new(),
+                provider: Some(spec)
+            });
+        }
+        for export in exports{
+            config.exports.retain(|existing| existing.name!=export.name);
+            config.exports.push(export);
+        }
+        Ok(config)
+    }
+}
+impl ServeOpt{
+    pub fn load_config(&self)->Result<Config, Box<dyn Error>>{
+        let mut config=self.exports.load_config()?;
+        if !self.listen.is_empty(){
+            config.listen=self.listen.clone();
+        }
+        if let Some(path)=&self.unix_socket{
+            config.unix_socket=Some(UnixSocketConfig{path: path.clone(), mode: self.unix_socket_mode.unwrap_or(DEFAULT_UNIX_SOCKET_MODE)});
+        }
+        if let Some(max_in_flight)=self.max_in_flight{
+            config.max_in_flight=max_in_flight;
+        }
+        let tls_required=self.tls_required || env_flag("CLOUDDRIVE_TLS_REQUIRED");
+        if let (Some(cert), Some(key))=(&self.tls_cert, &self.tls_key){
+            config.tls=Some(TlsConfig{cert: cert.clone(), key: key.clone(), client_ca: self.tls_client_ca.clone(), required: tls_required});
+        }else if let Some(tls)=&mut config.tls{
+            tls.required|=tls_required;
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use serde::Deserialize;
+use crate::support::*;
+use crate::support::registry::ProviderSpec;
+use crate::nbd::{Export, TlsOptions, DEFAULT_MAX_IN_FLIGHT};
+use crate::tls;
+
+pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:19191";
+/// Owner and group only, so filesystem permissions decide who may connect.
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+
+#[derive(Debug)]
+pub enum ConfigError{
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    DuplicateExport(String),
+    /// An export needs exactly one of `backend` and `provider`.
+    BadExport(String)
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self{
+            ConfigError::Io(err)=>write!(f, "Failed to read configuration: {}", err),
+            ConfigError::Parse(err)=>write!(f, "Bad configuration: {}", err),
+            ConfigError::DuplicateExport(name)=>write!(f, "Export {} is configured more than once", name),
+            ConfigError::BadExport(name)=>write!(f, "Export {} needs either a provider, or a backend with optional layers", name)
+        }
+    }
+}
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // Generic error, underlying cause isn't tracked.
+        None
+    }
+}
+impl From<std::io::Error> for ConfigError{
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+impl From<toml::de::Error> for ConfigError{
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+pub type Result<T>=std::result::Result<T, ConfigError>;
+
+/// Server configuration, usually loaded from a TOML file:
+///
+/// ```toml
+/// listen = ["127.0.0.1:19191"]
+///
+/// [[export]]
+/// name = "memory"
+/// size = 1073741824
+/// backend = { type = "memory" }
+/// layers = [{ type = "bytes" }, { type = "lru", capacity = 1024 }]
+///
+/// [[export]]
+/// name = "seafile"
+/// size = 1073741824
+/// provider = "bytes+lru(cap=1048576)+seafile://cloud.tsinghua.edu.cn/<library>"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config{
+    /// TCP addresses to accept connections on.
+    #[serde(default="default_listen")]
+    pub listen: Vec<String>,
+    pub unix_socket: Option<UnixSocketConfig>,
+    /// Requests served concurrently on each connection.
+    #[serde(default="default_max_in_flight")]
+    pub max_in_flight: usize,
+    pub tls: Option<TlsConfig>,
+    #[serde(default, rename="export")]
+    pub exports: Vec<ExportConfig>
+}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnixSocketConfig{
+    pub path: String,
+    #[serde(default="default_unix_socket_mode")]
+    pub mode: u32
+}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig{
+    pub cert: String,
+    pub key: String,
+    pub client_ca: Option<String>,
+    #[serde(default)]
+    pub required: bool
+}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportConfig{
+    pub name: String,
+    pub description: Option<String>,
+    /// Required unless the provider spec gives its own size.
+    pub size: Option<usize>,
+    #[serde(default)]
+    pub read_only: bool,
+    pub backend: Option<BackendConfig>,
+    /// Wrappers around the backend, outermost first.
+    #[serde(default)]
+    pub layers: Vec<LayerConfig>,
+    /// The whole stack as a registry spec, e.g. `bytes+lru(cap=1024)+memory`, instead of `backend` and `layers`.
+    pub provider: Option<String>
+}
+#[derive(Debug, Deserialize)]
+#[serde(tag="type", rename_all="lowercase", deny_unknown_fields)]
+pub enum BackendConfig{
+    Memory,
+    /// A local file or block device. A missing file is created with the export size, an existing one must match it.
+    File{path: String},
+    /// One file per block in a local directory, laid out like a Seafile library.
+    Directory{path: String, block_size: Option<usize>},
+    Seafile{
+        /// Falls back to the SEAFILE_TOKEN environment variable, to keep the token out of the file.
+        token: Option<String>,
+        library: String
+    },
+    /// Amazon S3 or a compatible service such as MinIO.
+    S3{
+        /// Defaults to AWS in the given region.
+        endpoint: Option<String>,
+        region: Option<String>,
+        bucket: String,
+        /// Prepended to the object names, usually ending with `/`.
+        #[serde(default)]
+        prefix: String,
+        /// Fall back to AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.
+        access_key: Option<String>,
+        secret_key: Option<String>,
+        /// Size of the objects blocks are stored in.
+        block_size: Option<usize>
+    },
+    /// A WebDAV collection, e.g. a Nextcloud folder.
+    WebDav{
+        /// e.g. `https://cloud.example.com/remote.php/dav/files/<user>/clouddrive/`.
+        url: String,
+        /// Basic authentication, falling back to WEBDAV_USER and WEBDAV_PASSWORD.
+        username: Option<String>,
+        password: Option<String>,
+        /// Bearer token instead of basic authentication, falling back to WEBDAV_TOKEN.
+        token: Option<String>,
+        block_size: Option<usize>,
+        /// Spreads the blocks over nested collections of this many blocks each.
+        fan_out: Option<usize>
+    },
+    /// A raw disk image on an HTTP server, read with `Range` requests. Read-only, so usually
+    /// wrapped in a `cow` layer. The size comes from the server, and the export size must match it if given.
+    Http{url: String, block_size: Option<usize>}
+}
+#[derive(Debug, Deserialize)]
+#[serde(tag="type", rename_all="lowercase", deny_unknown_fields)]
+pub enum LayerConfig{
+    Bytes,
+    Lru{capacity: usize},
+    /// Keeps writes in memory instead of passing them on.
+    Cow
+}
+
+/// Registry arguments left out when showing a stack.
+const CREDENTIAL_ARGS: &[&str]=&["token", "user", "password", "access_key", "secret_key"];
+/// A registry component with the arguments that are set.
+fn component(name: &str, args: &[(&str, Option<String>)], location: Option<&str>)->ProviderSpec{
+    ProviderSpec{
+        name: String::from(name),
+        args: args.iter().filter_map(|(key, value)| Some((String::from(*key), value.clone()?))).collect(),
+        location: location.map(String::from)
+    }
+}
+/// Splits a URL into scheme and the rest, taking `https` if it has no scheme.
+fn split_url(url: &str)->(&str, &str){
+    match url.find("://"){
+        Some(index)=>(&url[..index], &url[index+3..]),
+        None=>("https", url)
+    }
+}
+impl BackendConfig{
+    fn spec(&self)->ProviderSpec{
+        let number=|value: &Option<usize>| value.map(|value| value.to_string());
+        match self{
+            BackendConfig::Memory=>component("memory", &[], None),
+            BackendConfig::File{path}=>component("file", &[], Some(path)),
+            BackendConfig::Directory{path, block_size}=>component("directory", &[("block", number(block_size))], Some(path)),
+            BackendConfig::Seafile{token, library}=>component("seafile", &[("token", token.clone())], Some(library)),
+            BackendConfig::S3{endpoint, region, bucket, prefix, access_key, secret_key, block_size}=>component("s3", &[
+                ("endpoint", endpoint.clone()),
+                ("region", region.clone()),
+                ("access_key", access_key.clone()),
+                ("secret_key", secret_key.clone()),
+                ("block", number(block_size))
+            ], Some(&format!("{}/{}", bucket, prefix))),
+            BackendConfig::WebDav{url, username, password, token, block_size, fan_out}=>{
+                let (scheme, location)=split_url(url);
+                component("webdav", &[
+                    ("scheme", Some(String::from(scheme)).filter(|scheme| scheme!="https")),
+                    ("user", username.clone()),
+                    ("password", password.clone()),
+                    ("token", token.clone()),
+                    ("block", number(block_size)),
+                    ("fanout", number(fan_out))
+                ], Some(location))
+            }
+            BackendConfig::Http{url, block_size}=>{
+                let (scheme, location)=split_url(url);
+                component(scheme, &[("block", number(block_size))], Some(location))
+            }
+        }
+    }
+}
+impl LayerConfig{
+    fn spec(&self)->ProviderSpec{
+        match self{
+            LayerConfig::Bytes=>component("bytes", &[], None),
+            LayerConfig::Lru{capacity}=>component("lru", &[("cap", Some(capacity.to_string()))], None),
+            LayerConfig::Cow=>component("cow", &[], None)
+        }
+    }
+}
+
+fn default_listen()->Vec<String>{
+    vec![String::from(DEFAULT_LISTEN_ADDR)]
+}
+fn default_max_in_flight()->usize{
+    DEFAULT_MAX_IN_FLIGHT
+}
+fn default_unix_socket_mode()->u32{
+    DEFAULT_UNIX_SOCKET_MODE
+}
+
+impl Default for Config{
+    /// The configuration used without a configuration file: a 1 GiB in-memory disk, and a 1 GiB disk
+    /// in the Seafile library SEAFILE_LIBRARY if set, with the token from SEAFILE_TOKEN.
+    fn default()->Self{
+        let mut exports=vec![ExportConfig{
+            name: String::from("memory"),
+            description: Some(String::from("1 GiB in-memory disk")),
+            size: Some(1*1024*1024*1024),
+            read_only: false,
+            backend: Some(BackendConfig::Memory),
+            layers: vec![LayerConfig::Bytes, LayerConfig::Lru{capacity: 1024}],
+            provider: None
+        }];
+        if let Ok(library)=std::env::var("SEAFILE_LIBRARY"){
+            exports.push(ExportConfig{
+                name: String::from("seafile"),
+                description: Some(String::from("1 GiB disk in a Seafile library")),
+                size: Some(1*1024*1024*1024),
+                read_only: false,
+                backend: Some(BackendConfig::Seafile{token: None, library}),
+                layers: vec![LayerConfig::Bytes, LayerConfig::Lru{capacity: 1024*1024}],
+                provider: None
+            });
+        }
+        Config{
+            listen: default_listen(),
+            unix_socket: None,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            tls: None,
+            exports
+        }
+    }
+}
+impl Config{
+    pub fn load(path: &str)->Result<Config>{
+        let content=std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+    pub fn tls_options(&self)->std::result::Result<Option<Arc<TlsOptions>>, Box<dyn Error>>{
+        match &self.tls{
+            Some(config)=>{
+                let acceptor=tls::load_acceptor(&config.cert, &config.key, config.client_ca.as_deref())?;
+                Ok(Some(Arc::new(TlsOptions{acceptor, required: config.required})))
+            }
+            None=>Ok(None)
+        }
+    }
+    /// Connects every backend and assembles the exports.
+    pub async fn build_exports(&self)->std::result::Result<BTreeMap<String, Export>, Box<dyn Error>>{
+        let registry=Registry::default();
+        let mut exports=BTreeMap::new();
+        for config in self.exports.iter(){
+            if exports.contains_key(&config.name){
+                return Err(ConfigError::DuplicateExport(config.name.clone()))?;
+            }
+            exports.insert(config.name.clone(), config.build_export(&registry).await?);
+        }
+        Ok(exports)
+    }
+}
+impl ExportConfig{
+    pub async fn build_export(&self, registry: &Registry)->std::result::Result<Export, Box<dyn Error>>{
+        Ok(Export::new(self.build_provider(registry).await?, self.description.as_deref()).read_only(self.read_only))
+    }
+    pub async fn build_provider(&self, registry: &Registry)->std::result::Result<Box<dyn CloudProvider>, Box<dyn Error>>{
+        match (&self.backend, &self.provider){
+            (None, Some(spec)) if self.layers.is_empty()=>registry.build(spec, self.size).await,
+            (Some(_), None)=>registry.build_stack(&self.specs(), self.size).await,
+            _=>Err(ConfigError::BadExport(self.name.clone()))?
+        }
+    }
+    /// The `backend` and `layers` tables as registry components, so that the factories handle their arguments.
+    fn specs(&self)->Vec<ProviderSpec>{
+        let mut specs: Vec<ProviderSpec>=self.layers.iter().map(LayerConfig::spec).collect();
+        specs.extend(self.backend.as_ref().map(BackendConfig::spec));
+        specs
+    }
+    /// The provider stack in registry spec syntax, for display. Credentials are left out.
+    pub fn stack(&self)->String{
+        if let Some(spec)=&self.provider{
+            return spec.clone();
+        }
+        let specs: Vec<String>=self.specs().into_iter().map(|mut spec| {
+            spec.args.retain(|key, _| !CREDENTIAL_ARGS.contains(&key.as_str()));
+            spec.to_string()
+        }).collect();
+        specs.join("+")
+    }
+}
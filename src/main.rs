@@ -1,45 +1,203 @@
 #![deny(unused_must_use)]
 #![feature(slice_index_methods)]
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, UnixListener};
 use tokio::prelude::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
 use tokio;
-use tokio::sync::Mutex;
 use std::collections::BTreeMap;
 use std::sync::{Arc};
-use crate::support::*;
-use crate::nbd::handle_packet;
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use structopt::StructOpt;
+use crate::cli::{Command, ExportOpt, Opt};
+use crate::config::Config;
+use crate::nbd::{handle_packet, wait_for_shutdown, Export, NBDStream, TlsOptions};
+use crate::support::Registry;
 
 mod nbd;
 mod support;
 mod utils;
-const CLOUDDRIVE_ADDR: &str = "127.0.0.1:19191";
+mod tls;
+mod config;
+mod cli;
+
+/// Pause after a failed accept before trying again.
+const ACCEPT_RETRY_DELAY: tokio::time::Duration=tokio::time::Duration::from_millis(100);
+/// Everything a connection needs from the server.
+#[derive(Clone)]
+struct ServerContext{
+    providers: Arc<BTreeMap<String, Export>>,
+    tls_options: Option<Arc<TlsOptions>>,
+    max_in_flight: usize,
+    shutdown: watch::Receiver<bool>,
+    /// Held by every connection, so that the server can tell when the last one is gone.
+    connections: mpsc::Sender<()>
+}
+fn serve_connection(stream: Box<dyn NBDStream>, mut context: ServerContext){
+    tokio::spawn(async move {
+            // A client going away mid-handshake or without NBD_CMD_DISC only ends its own connection.
+            let (stream, provider, options)=tokio::select!{
+                handshake=nbd::handshake(stream, context.providers.as_ref(), context.tls_options.as_deref())=>match handshake{
+                    Ok(negotiated)=>negotiated,
+                    Err(err)=>return eprintln!("Handshake failed: {:?}", err)
+                },
+                _=wait_for_shutdown(&mut context.shutdown)=>return
+            };
+            if let Err(err)=handle_packet(stream, provider, options, context.max_in_flight, context.shutdown.clone()).await{
+                eprintln!("Connection closed: {:?}", err);
+            }
+            drop(context.connections);
+    });
+}
+/// Logs a failed accept and pauses, since errors such as running out of file descriptors only pass
+/// once connections close.
+async fn accept_failed(err: std::io::Error){
+    eprintln!("Failed to accept connection: {:?}", err);
+    tokio::time::delay_for(ACCEPT_RETRY_DELAY).await;
+}
+/// Binds the unix socket at `path` with permissions `mode`. The socket is bound in a private directory and
+/// only moved into place once its permissions are set, so that it is never reachable with looser ones.
+/// A socket left behind by a previous run is replaced, but one that is still listened on, or anything
+/// that is not a socket, is left alone.
+fn bind_unix_socket(path: &str, mode: u32)->Result<UnixListener, Box<dyn std::error::Error>>{
+    match std::fs::symlink_metadata(path){
+        Ok(metadata) if !metadata.file_type().is_socket()=>return Err(format!("{} exists and is not a socket", path))?,
+        Ok(_)=>match std::os::unix::net::UnixStream::connect(path){
+            Err(err) if err.kind()==ErrorKind::ConnectionRefused=>println!("Replacing stale unix socket {}", path),
+            Ok(_)=>return Err(format!("Unix socket {} is in use", path))?,
+            Err(err)=>return Err(err)?
+        },
+        Err(err) if err.kind()==ErrorKind::NotFound=>{},
+        Err(err)=>return Err(err)?
+    }
+    let private=format!("{}.{}.tmp", path, std::process::id());
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let socket=format!("{}/socket", private);
+    let listener=UnixListener::bind(&socket).and_then(|listener| {
+        std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&socket, path)?;
+        Ok(listener)
+    });
+    std::fs::remove_dir_all(&private)?;
+    Ok(listener?)
+}
+async fn wait_for_signal() -> Result<(), Box<dyn std::error::Error>> {
+    let mut interrupt=signal(SignalKind::interrupt())?;
+    let mut terminate=signal(SignalKind::terminate())?;
+    tokio::select!{
+        _=interrupt.recv()=>println!("SIGINT received."),
+        _=terminate.recv()=>println!("SIGTERM received.")
+    }
+    Ok(())
+}
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    match Opt::from_args().command{
+        Some(Command::Serve(opt))=>serve(opt.load_config()?).await,
+        None=>serve(cli::ServeOpt::from_iter(std::env::args().take(1)).load_config()?).await,
+        Some(Command::ListExports(opt))=>list_exports(&opt),
+        Some(Command::Info{export, exports})=>info(&export, &exports).await
+    }
+}
+fn list_exports(opt: &ExportOpt) -> Result<(), Box<dyn std::error::Error>> {
+    let config=opt.load_config()?;
+    for export in config.exports.iter(){
+        let size=export.size.map(|size| size.to_string()).unwrap_or(String::from("-"));
+        println!("{}\t{}\t{}{}\t{}", export.name, size, export.stack(), if export.read_only {" (read-only)"} else {""}, export.description.as_deref().unwrap_or(""));
+    }
+    Ok(())
+}
+async fn info(name: &str, opt: &ExportOpt) -> Result<(), Box<dyn std::error::Error>> {
+    let config=opt.load_config()?;
+    let export_config=match config.exports.iter().find(|export| export.name==name){
+        Some(export)=>export,
+        None=>return Err(format!("Unknown export: {}", name))?
+    };
+    let export=export_config.build_export(&Registry::default()).await?;
+    println!("name: {}", name);
+    println!("stack: {}", export_config.stack());
+    if let Some(description)=&export.description{
+        println!("description: {}", description);
+    }
+    println!("size: {}", export.provider.total_size());
+    println!("block size: {}", export.provider.block_size());
+    println!("read-only: {}", export.read_only);
+    println!("capabilities: {:?}", export.provider.capabilities());
+    Ok(())
+}
+async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
     println!("CloudDrive Started!");
-    let mut listener = TcpListener::bind(&CLOUDDRIVE_ADDR).await?;
+    let tls_options=config.tls_options()?;
+    let providers=Arc::new(config.build_exports().await?);
+    let (shutdown_sender, shutdown)=watch::channel(false);
+    let (connections, mut connections_done)=mpsc::channel::<()>(1);
+    let context=ServerContext{providers: Arc::clone(&providers), tls_options, max_in_flight: config.max_in_flight, shutdown, connections};
 
-    let providers={
-        let mut providers: BTreeMap<String, Arc<Mutex<Box<dyn CloudProvider>>>>=BTreeMap::new();
-        providers.insert(String::from("memory"), Arc::new(Mutex::new(Box::new(ByteGranularityProvider::new(LRUProvider::new(MemoryProvider::new(1*1024*1024*1024), 1024))))));
-        providers.insert(String::from("seafile"), Arc::new(Mutex::new(Box::new(
-            ByteGranularityProvider::new(
-                LRUProvider::new(
-                    SeafileProvider::connect(&std::env::var("SEAFILE_TOKEN").expect("SEAFILE_TOKEN missing!"), &std::env::var("SEAFILE_LIBRARY").expect("SEAFILE_LIBRARY missing!"), 1*1024*1024*1024).await?
-                    ,1024*1024
-                )
-            )
-        ))));
-
-        Arc::new(providers)
-    };
+    let mut listeners=Vec::new();
+    if let Some(unix_socket)=&config.unix_socket{
+        let path=&unix_socket.path;
+        let mut unix_listener=bind_unix_socket(path, unix_socket.mode)?;
+        println!("Listening on unix socket {}", path);
+        let mut context=context.clone();
+        let path=path.clone();
+        listeners.push(tokio::spawn(async move {
+            loop {
+                let accepted=tokio::select!{
+                    accepted=unix_listener.accept()=>accepted,
+                    _=wait_for_shutdown(&mut context.shutdown)=>break
+                };
+                match accepted{
+                    Ok((socket, _))=>serve_connection(Box::new(socket), context.clone()),
+                    Err(err)=>accept_failed(err).await
+                }
+            }
+            if let Err(err)=std::fs::remove_file(&path){
+                eprintln!("Failed to remove unix socket {}: {:?}", path, err);
+            }
+        }));
+    }
+    for addr in config.listen.iter(){
+        let mut listener = TcpListener::bind(addr).await?;
+        println!("Listening on {}", addr);
+        let mut context=context.clone();
+        listeners.push(tokio::spawn(async move {
+            loop {
+                let accepted=tokio::select!{
+                    accepted=listener.accept()=>accepted,
+                    _=wait_for_shutdown(&mut context.shutdown)=>break
+                };
+                match accepted{
+                    Ok((socket, _))=>serve_connection(Box::new(socket), context.clone()),
+                    Err(err)=>accept_failed(err).await
+                }
+            }
+        }));
+    }
     println!("CloudDrive Started!");
-    loop {
-        let (mut socket, _) = listener.accept().await?;
-        let ref_providers=Arc::clone(&providers);
-        tokio::spawn(async move {
-                let provider = nbd::handshake(&mut socket, ref_providers.as_ref()).await.unwrap();
-                let mut lock=provider.lock().await;
-                handle_packet(&mut socket, &mut *lock).await.unwrap();
-        });
-    }
-}
\ No newline at end of file
+    wait_for_signal().await?;
+    println!("Shutting down.");
+    shutdown_sender.broadcast(true)?;
+    for listener in listeners{
+        listener.await?;
+    }
+    // The channel closes once every connection has answered its pending requests and dropped its sender.
+    drop(context);
+    connections_done.recv().await;
+    // Flushed from a spawned task, since providers may block in place, which the main task cannot do.
+    let failed=tokio::spawn(async move {
+        let mut failed=Vec::new();
+        for (name, export) in providers.iter(){
+            if let Err(err)=export.provider.flush().await{
+                eprintln!("Failed to flush export {}: {:?}", name, err);
+                failed.push(name.clone());
+            }
+        }
+        failed
+    }).await?;
+    if !failed.is_empty(){
+        return Err(format!("Exports not flushed, data may have been lost: {}", failed.join(", ")))?;
+    }
+    println!("All exports flushed.");
+    Ok(())
+}
@@ -2,33 +2,57 @@ use tokio::prelude::*;
 use std::error::Error;
 use std::*;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, watch, Semaphore};
 use std::mem::MaybeUninit;
-use crate::support::{CloudProvider, SharedProvider, bound_and_align_check, MutexProvider};
+use crate::support::{CloudProvider, SharedProvider, LockedProvider, bound_and_align_check, Extent};
 use std::sync::Arc;
 use std::collections::BTreeMap;
+use bytes::{Buf, BufMut};
+use tokio_rustls::TlsAcceptor;
 
 const NBDMAGIC:u64=0x4e42444d41474943;
 const IHAVEOPT:u64=0x49484156454F5054;
-const HANDSHAKE_FLAGS:u16=1; // Does not support NBD_FLAG_NO_ZEROES
+const NBD_FLAG_FIXED_NEWSTYLE:u16=1<<0;
+const NBD_FLAG_NO_ZEROES:u16=1<<1;
+const HANDSHAKE_FLAGS:u16=NBD_FLAG_FIXED_NEWSTYLE|NBD_FLAG_NO_ZEROES;
+const NBD_FLAG_C_FIXED_NEWSTYLE:u32=1<<0;
+const NBD_FLAG_C_NO_ZEROES:u32=1<<1;
 const REPLY_OPT:u64=0x3e889045565a9;
 
 pub const PREFERRED_BLOCK_SIZE:usize=4096;
+/// Largest payload of a single NBD_CMD_READ or NBD_CMD_WRITE, advertised through NBD_INFO_BLOCK_SIZE.
+pub const MAX_BLOCK_SIZE:usize=32*1024*1024;
+/// Default number of requests a single connection may have in flight.
+pub const DEFAULT_MAX_IN_FLIGHT:usize=16;
 
 const NBD_OPT_EXPORT_NAME:u32=1;
 const NBD_OPT_ABORT:u32=2;
 const NBD_OPT_LIST:u32=3;
+const NBD_OPT_STARTTLS:u32=5;
 
 const NBD_OPT_INFO:u32=6;
 const NBD_OPT_GO:u32=7;
+const NBD_OPT_STRUCTURED_REPLY:u32=8;
+const NBD_OPT_LIST_META_CONTEXT:u32=9;
+const NBD_OPT_SET_META_CONTEXT:u32=10;
+const NBD_OPT_EXTENDED_HEADERS:u32=11;
 
 const NBD_REP_ACK:u32=1;
-const NBD_REP_SERVER:u32=1;
+const NBD_REP_SERVER:u32=2;
 const NBD_REP_INFO:u32=3;
+const NBD_REP_META_CONTEXT:u32=4;
 
 const NBD_REP_ERR_PREFIX:u32=2147483648;
 const NBD_REP_ERR_UNSUP:u32=NBD_REP_ERR_PREFIX+1;
 const NBD_REP_ERR_INVALID:u32=NBD_REP_ERR_PREFIX+3;
+const NBD_REP_ERR_TLS_REQD:u32=NBD_REP_ERR_PREFIX+5;
+const NBD_REP_ERR_UNKNOWN:u32=NBD_REP_ERR_PREFIX+6;
+const NBD_REP_ERR_BLOCK_SIZE_REQD:u32=NBD_REP_ERR_PREFIX+8;
+
+const NBD_INFO_EXPORT:u16=0;
+const NBD_INFO_NAME:u16=1;
+const NBD_INFO_DESCRIPTION:u16=2;
+const NBD_INFO_BLOCK_SIZE:u16=3;
 
 
 const NBD_FLAG_HAS_FLAGS:u16=1<<0;
@@ -44,7 +68,11 @@ const NBD_FLAG_SEND_RESIZE:u16=1<<9;
 const NBD_FLAG_SEND_CACHE:u16=1<<10;
 const NBD_FLAG_SEND_FAST_ZERO:u16=1<<11;
 
+/// Flags advertised for every export. The rest depend on the export and its provider.
+const TRANSMISSION_FLAGS:u16=NBD_FLAG_HAS_FLAGS|NBD_FLAG_SEND_FLUSH|NBD_FLAG_CAN_MULTI_CONN|NBD_FLAG_SEND_CACHE;
+
 const NBD_REQUEST_MAGIC:u32=0x25609513;
+const NBD_EXTENDED_REQUEST_MAGIC:u32=0x21e41c71;
 
 const NBD_CMD_FLAG_FUA:u16=1<<0;
 const NBD_CMD_FLAG_NO_HOLE:u16=1<<1;
@@ -64,6 +92,32 @@ const NBD_CMD_BLOCK_STATUS:u16=7;
 const NBD_CMD_RESIZE:u16=8;
 
 const NBD_SIMPLE_REPLY_MAGIC:u32=0x67446698;
+const NBD_STRUCTURED_REPLY_MAGIC:u32=0x668e33ef;
+const NBD_EXTENDED_REPLY_MAGIC:u32=0x6e8a278c;
+
+const NBD_REPLY_FLAG_DONE:u16=1<<0;
+
+const NBD_REPLY_TYPE_NONE:u16=0;
+const NBD_REPLY_TYPE_OFFSET_DATA:u16=1;
+const NBD_REPLY_TYPE_OFFSET_HOLE:u16=2;
+const NBD_REPLY_TYPE_BLOCK_STATUS:u16=5;
+const NBD_REPLY_TYPE_BLOCK_STATUS_EXT:u16=6;
+const NBD_REPLY_TYPE_ERROR:u16=(1<<15)+1;
+const NBD_REPLY_TYPE_ERROR_OFFSET:u16=(1<<15)+2;
+
+const NBD_STATE_HOLE:u32=1<<0;
+const NBD_STATE_ZERO:u32=1<<1;
+
+const BASE_ALLOCATION_CONTEXT_ID:u32=1;
+/// Metadata contexts known to the server, with the ids they are selected under.
+const META_CONTEXTS:[(u32, &str);1]=[(BASE_ALLOCATION_CONTEXT_ID, "base:allocation")];
+
+/// Reads with structured replies are split into chunks of at most this size.
+const READ_CHUNK_SIZE:usize=128*1024;
+/// How long a client may keep the connection open once the server starts shutting down.
+const SHUTDOWN_TIMEOUT:tokio::time::Duration=tokio::time::Duration::from_secs(10);
+/// How long requests in flight and their replies may take once the connection is closing.
+const DRAIN_TIMEOUT:tokio::time::Duration=tokio::time::Duration::from_secs(30);
 
 const NBD_EPERM:u32=1;
 const NBD_EIO:u32=5;
@@ -80,7 +134,9 @@ enum NBDError {
     ClientOptionsError,
     BadExportError,
     Abort,
-    RequestMagicError
+    RequestMagicError,
+    ConnectionClosed,
+    TlsRequiredError
 }
 
 // Generation of an error is completely separate from how it is displayed.
@@ -101,6 +157,15 @@ impl Error for NBDError {
         None
     }
 }
+/// Any byte stream a connection can run over, before or after the TLS upgrade.
+pub trait NBDStream: AsyncRead+AsyncWrite+Unpin+Send {}
+impl<T: AsyncRead+AsyncWrite+Unpin+Send> NBDStream for T {}
+/// Settings for upgrading connections with NBD_OPT_STARTTLS.
+pub struct TlsOptions{
+    pub acceptor: TlsAcceptor,
+    /// Refuse every other option until the connection has been upgraded.
+    pub required: bool
+}
 struct NBDClient<T: AsyncRead+AsyncWrite+Unpin>{
     stream: T
 }
@@ -114,9 +179,57 @@ struct OptionReply{
     pub reply_type: u32,
     pub data: Vec<u8>,
 }
+/// Options agreed upon during the handshake that affect the transmission phase.
+#[derive(Debug, Clone, Default)]
+pub struct NegotiatedOptions{
+    pub structured_replies: bool,
+    /// 64-bit request and reply headers, which imply structured replies.
+    pub extended_headers: bool,
+    /// Metadata contexts selected by NBD_OPT_SET_META_CONTEXT.
+    pub meta_contexts: Vec<u32>,
+    /// The export the metadata contexts were selected for.
+    pub meta_context_export: Option<String>,
+    /// Transmission flags advertised for the chosen export.
+    pub transmission_flags: u16
+}
 struct ExportItem{
     pub size: u64,
-    pub transmission_flags: u16
+    pub transmission_flags: u16,
+    /// The client did not set NBD_FLAG_C_NO_ZEROES and expects the reply to be padded.
+    pub pad_zeroes: bool
+}
+/// An export offered to clients: the provider together with its advertised metadata.
+/// The provider is shared by all connections to the export.
+pub struct Export{
+    pub provider: Arc<dyn SharedProvider>,
+    pub description: Option<String>,
+    /// Clients may only read from the export.
+    pub read_only: bool
+}
+impl Export{
+    pub fn new<T: CloudProvider+'static>(provider: T, description: Option<&str>)->Self{
+        let read_only=provider.capabilities().read_only;
+        Export{
+            provider: Arc::new(LockedProvider::new(provider)),
+            description: description.map(String::from),
+            read_only
+        }
+    }
+    /// Makes the export read-only. Exports of read-only providers stay read-only either way.
+    pub fn read_only(mut self, read_only: bool)->Self{
+        self.read_only=read_only || self.provider.capabilities().read_only;
+        self
+    }
+}
+/// Payload of NBD_OPT_INFO and NBD_OPT_GO.
+struct InfoRequest{
+    pub name: String,
+    pub requests: Vec<u16>
+}
+/// Payload of NBD_OPT_LIST_META_CONTEXT and NBD_OPT_SET_META_CONTEXT.
+struct MetaContextRequest{
+    pub name: String,
+    pub queries: Vec<String>
 }
 
 async fn read_nbd_client_option<T: AsyncRead+Unpin>(stream: &mut T)->Result<ClientOption, Box<dyn Error>>{
@@ -144,46 +257,324 @@ async fn write_nbd_option_reply<T: AsyncWrite + Unpin>(stream: &mut T, reply: Op
 async fn write_nbd_export_item<T: AsyncWrite + Unpin>(stream: &mut T, reply: ExportItem)->Result<(), Box<dyn Error>>{
     stream.write_u64(reply.size).await?;
     stream.write_u16(reply.transmission_flags).await?;
-    //let zero:[u8;124]=[0;124];
-    //stream.write_all(&zero).await?;
+    if reply.pad_zeroes{
+        let zero:[u8;124]=[0;124];
+        stream.write_all(&zero).await?;
+    }
+    Ok(())
+}
+/// Transmission flags advertised for an export, computed from its settings and the capabilities of its provider.
+fn transmission_flags(export: &Export)->u16{
+    let capabilities=export.provider.capabilities();
+    let mut flags=TRANSMISSION_FLAGS;
+    if export.read_only{
+        flags|=NBD_FLAG_READ_ONLY;
+    }else{
+        flags|=NBD_FLAG_SEND_WRITE_ZEROES|NBD_FLAG_SEND_FAST_ZERO;
+        if capabilities.trim{
+            flags|=NBD_FLAG_SEND_TRIM;
+        }
+        if capabilities.fua{
+            flags|=NBD_FLAG_SEND_FUA;
+        }
+        if capabilities.resize{
+            flags|=NBD_FLAG_SEND_RESIZE;
+        }
+    }
+    if capabilities.rotational{
+        flags|=NBD_FLAG_ROTATIONAL;
+    }
+    flags
+}
+fn parse_info_request(data: &[u8])->Option<InfoRequest>{
+    let mut buf=data;
+    if buf.remaining()<4 {
+        return None;
+    }
+    let name_length=buf.get_u32() as usize;
+    if buf.remaining()<name_length+2 {
+        return None;
+    }
+    let name=String::from_utf8(buf[..name_length].to_vec()).ok()?;
+    buf.advance(name_length);
+    let count=buf.get_u16() as usize;
+    if buf.remaining()!=count*2 {
+        return None;
+    }
+    let requests=(0..count).map(|_| buf.get_u16()).collect();
+    Some(InfoRequest{name, requests})
+}
+fn parse_meta_context_request(data: &[u8])->Option<MetaContextRequest>{
+    fn read_string(buf: &mut &[u8])->Option<String>{
+        if buf.remaining()<4 {
+            return None;
+        }
+        let length=buf.get_u32() as usize;
+        if buf.remaining()<length {
+            return None;
+        }
+        let string=String::from_utf8(buf[..length].to_vec()).ok()?;
+        buf.advance(length);
+        Some(string)
+    }
+    let mut buf=data;
+    let name=read_string(&mut buf)?;
+    if buf.remaining()<4 {
+        return None;
+    }
+    let count=buf.get_u32();
+    let mut queries=Vec::new();
+    for _ in 0..count{
+        queries.push(read_string(&mut buf)?);
+    }
+    if buf.has_remaining() {
+        return None;
+    }
+    Some(MetaContextRequest{name, queries})
+}
+/// Resolves a meta context request to the matching contexts.
+/// Listing accepts namespace queries such as `base:`, while selecting only accepts full names.
+fn match_meta_contexts(request: &MetaContextRequest, list: bool)->Vec<(u32, &'static str)>{
+    if list && request.queries.is_empty(){
+        return META_CONTEXTS.to_vec();
+    }
+    let mut contexts=Vec::new();
+    for query in request.queries.iter(){
+        for (id, name) in META_CONTEXTS.iter(){
+            let matched=query==name || (list && query.ends_with(':') && name.starts_with(query.as_str()));
+            if matched && !contexts.iter().any(|(selected, _)| selected==id){
+                contexts.push((*id, *name));
+            }
+        }
+    }
+    contexts
+}
+async fn write_nbd_info_replies<T: AsyncWrite + Unpin>(stream: &mut T, option: u32, request: &InfoRequest, export: &Export)->Result<(), Box<dyn Error>>{
+    let (block_size, total_size)=(export.provider.block_size(), export.provider.total_size());
+    // NBD_INFO_EXPORT is mandatory and always comes first.
+    let mut data=Vec::new();
+    data.put_u16(NBD_INFO_EXPORT);
+    data.put_u64(total_size as u64);
+    data.put_u16(transmission_flags(export));
+    write_nbd_option_reply(stream, OptionReply{option, reply_type: NBD_REP_INFO, data}).await?;
+    for info in request.requests.iter(){
+        let mut data=Vec::new();
+        match *info{
+            NBD_INFO_NAME=>{
+                data.put_u16(NBD_INFO_NAME);
+                data.put_slice(request.name.as_bytes());
+            }
+            NBD_INFO_DESCRIPTION=>{
+                if let Some(description)=&export.description{
+                    data.put_u16(NBD_INFO_DESCRIPTION);
+                    data.put_slice(description.as_bytes());
+                }else{
+                    continue;
+                }
+            }
+            NBD_INFO_BLOCK_SIZE=>{
+                data.put_u16(NBD_INFO_BLOCK_SIZE);
+                data.put_u32(block_size as u32);
+                data.put_u32(cmp::max(block_size, PREFERRED_BLOCK_SIZE) as u32);
+                data.put_u32(MAX_BLOCK_SIZE as u32);
+            }
+            _=>{
+                // Unknown information types are silently ignored.
+                continue;
+            }
+        }
+        write_nbd_option_reply(stream, OptionReply{option, reply_type: NBD_REP_INFO, data}).await?;
+    }
     Ok(())
 }
 
 
-pub async fn handshake<T: AsyncRead+AsyncWrite+Unpin>(stream: &mut T, exports: &BTreeMap<String, Arc<Mutex<Box<dyn CloudProvider>>>>)->Result<Arc<Mutex<Box<dyn CloudProvider>>>, Box<dyn Error>>{
+pub async fn handshake(stream: Box<dyn NBDStream>, exports: &BTreeMap<String, Export>, tls: Option<&TlsOptions>)->Result<(Box<dyn NBDStream>, Arc<dyn SharedProvider>, NegotiatedOptions), Box<dyn Error>>{
+    let mut stream=stream;
     println!("Incoming handshake...");
     stream.write_u64(NBDMAGIC).await?;
     stream.write_u64(IHAVEOPT).await?;
     stream.write_u16(HANDSHAKE_FLAGS).await?;
     stream.flush().await?;
     let client_flags=stream.read_u32().await?;
-    if client_flags!=1 {
+    // Option replies are only understood by fixed newstyle clients, so others are hung up on.
+    if client_flags & !(NBD_FLAG_C_FIXED_NEWSTYLE|NBD_FLAG_C_NO_ZEROES)!=0 || client_flags & NBD_FLAG_C_FIXED_NEWSTYLE==0 {
         return Err(NBDError::ClientFlagsError)?;
     }
+    let mut options=NegotiatedOptions::default();
+    let mut tls_active=false;
     loop {
-        let option=read_nbd_client_option(stream).await?;
+        let option=read_nbd_client_option(&mut stream).await?;
+        if tls.map(|tls| tls.required).unwrap_or(false) && !tls_active{
+            match option.option{
+                NBD_OPT_STARTTLS | NBD_OPT_ABORT=>{}
+                NBD_OPT_EXPORT_NAME=>{
+                    // There is no way to refuse NBD_OPT_EXPORT_NAME but hanging up.
+                    return Err(NBDError::TlsRequiredError)?;
+                }
+                _=>{
+                    println!("Option {} refused before TLS", option.option);
+                    write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_TLS_REQD, data: Vec::new()}).await?;
+                    stream.flush().await?;
+                    continue;
+                }
+            }
+        }
         match option.option{
             NBD_OPT_EXPORT_NAME=>{
                 println!("NBD_OPT_EXPORT_NAME");
                 let name=String::from_utf8(Vec::clone(&option.data))?;
-                if let Some(provider)=exports.get(&name){
-                    write_nbd_export_item(stream, ExportItem {size: provider.lock().await.total_size() as u64, transmission_flags: NBD_FLAG_HAS_FLAGS|NBD_FLAG_SEND_FLUSH}).await?;
+                if let Some(export)=exports.get(&name){
+                    if options.meta_context_export.as_ref()!=Some(&name){
+                        options.meta_contexts.clear();
+                    }
+                    options.transmission_flags=transmission_flags(export);
+                    write_nbd_export_item(&mut stream, ExportItem {size: export.provider.total_size() as u64, transmission_flags: options.transmission_flags, pad_zeroes: client_flags&NBD_FLAG_C_NO_ZEROES==0}).await?;
                     stream.flush().await?;
-                    return Ok(Arc::clone(provider));
+                    return Ok((stream, Arc::clone(&export.provider), options));
                 }else{
                     return Err(NBDError::BadExportError)?;
                 }
 
             }
+            NBD_OPT_INFO | NBD_OPT_GO=>{
+                println!("{}", if option.option==NBD_OPT_GO {"NBD_OPT_GO"} else {"NBD_OPT_INFO"});
+                let request=match parse_info_request(&option.data){
+                    Some(request)=>request,
+                    None=>{
+                        write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_INVALID, data: Vec::new()}).await?;
+                        stream.flush().await?;
+                        continue;
+                    }
+                };
+                if let Some(export)=exports.get(&request.name){
+                    let block_size=export.provider.block_size();
+                    if option.option==NBD_OPT_GO && block_size>1 && !request.requests.contains(&NBD_INFO_BLOCK_SIZE){
+                        // The client would not honour our alignment constraints.
+                        write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_BLOCK_SIZE_REQD, data: Vec::new()}).await?;
+                        stream.flush().await?;
+                        continue;
+                    }
+                    write_nbd_info_replies(&mut stream, option.option, &request, export).await?;
+                    write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ACK, data: Vec::new()}).await?;
+                    stream.flush().await?;
+                    if option.option==NBD_OPT_GO{
+                        if options.meta_context_export.as_ref()!=Some(&request.name){
+                            options.meta_contexts.clear();
+                        }
+                        options.transmission_flags=transmission_flags(export);
+                        return Ok((stream, Arc::clone(&export.provider), options));
+                    }
+                }else{
+                    println!("Unknown export: {}", request.name);
+                    let message=format!("Unknown export: {}", request.name).into_bytes();
+                    write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_UNKNOWN, data: message}).await?;
+                    stream.flush().await?;
+                }
+            }
+            NBD_OPT_LIST=>{
+                println!("NBD_OPT_LIST");
+                if !option.data.is_empty(){
+                    write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_INVALID, data: Vec::new()}).await?;
+                    stream.flush().await?;
+                    continue;
+                }
+                for (name, export) in exports.iter(){
+                    let mut data=Vec::new();
+                    data.put_u32(name.len() as u32);
+                    data.put_slice(name.as_bytes());
+                    if let Some(description)=&export.description{
+                        data.put_slice(description.as_bytes());
+                    }
+                    write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_SERVER, data}).await?;
+                }
+                write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ACK, data: Vec::new()}).await?;
+                stream.flush().await?;
+            }
+            NBD_OPT_STRUCTURED_REPLY=>{
+                println!("NBD_OPT_STRUCTURED_REPLY");
+                let reply_type=if option.data.is_empty() {
+                    options.structured_replies=true;
+                    NBD_REP_ACK
+                }else{
+                    NBD_REP_ERR_INVALID
+                };
+                write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type, data: Vec::new()}).await?;
+                stream.flush().await?;
+            }
+            NBD_OPT_EXTENDED_HEADERS=>{
+                println!("NBD_OPT_EXTENDED_HEADERS");
+                let reply_type=if option.data.is_empty() {
+                    options.extended_headers=true;
+                    options.structured_replies=true;
+                    NBD_REP_ACK
+                }else{
+                    NBD_REP_ERR_INVALID
+                };
+                write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type, data: Vec::new()}).await?;
+                stream.flush().await?;
+            }
+            NBD_OPT_LIST_META_CONTEXT | NBD_OPT_SET_META_CONTEXT=>{
+                let list=option.option==NBD_OPT_LIST_META_CONTEXT;
+                println!("{}", if list {"NBD_OPT_LIST_META_CONTEXT"} else {"NBD_OPT_SET_META_CONTEXT"});
+                let request=match parse_meta_context_request(&option.data){
+                    Some(request) if list || options.structured_replies=>request,
+                    _=>{
+                        write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_INVALID, data: Vec::new()}).await?;
+                        stream.flush().await?;
+                        continue;
+                    }
+                };
+                if !exports.contains_key(&request.name){
+                    let message=format!("Unknown export: {}", request.name).into_bytes();
+                    write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_UNKNOWN, data: message}).await?;
+                    stream.flush().await?;
+                    continue;
+                }
+                let contexts=match_meta_contexts(&request, list);
+                for (id, name) in contexts.iter(){
+                    let mut data=Vec::new();
+                    data.put_u32(*id);
+                    data.put_slice(name.as_bytes());
+                    write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_META_CONTEXT, data}).await?;
+                }
+                if !list{
+                    options.meta_contexts=contexts.iter().map(|(id, _)| *id).collect();
+                    options.meta_context_export=Some(request.name);
+                }
+                write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ACK, data: Vec::new()}).await?;
+                stream.flush().await?;
+            }
+            NBD_OPT_STARTTLS=>{
+                println!("NBD_OPT_STARTTLS");
+                match tls{
+                    Some(tls) if !tls_active && option.data.is_empty()=>{
+                        write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ACK, data: Vec::new()}).await?;
+                        stream.flush().await?;
+                        stream=Box::new(tls.acceptor.accept(stream).await?);
+                        tls_active=true;
+                        // Nothing negotiated in plaintext carries over.
+                        options=NegotiatedOptions::default();
+                    }
+                    Some(_)=>{
+                        write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_INVALID, data: Vec::new()}).await?;
+                        stream.flush().await?;
+                    }
+                    None=>{
+                        write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_UNSUP, data: Vec::new()}).await?;
+                        stream.flush().await?;
+                    }
+                }
+            }
             NBD_OPT_ABORT=>{
                 println!("NBD_ABORT");
-                write_nbd_option_reply(stream, OptionReply{option: NBD_OPT_ABORT, reply_type: NBD_REP_ACK, data: Vec::new()}).await?;
+                write_nbd_option_reply(&mut stream, OptionReply{option: NBD_OPT_ABORT, reply_type: NBD_REP_ACK, data: Vec::new()}).await?;
                 stream.flush().await?;
                 return Err(NBDError::Abort)?;
             }
             _=>{
                 println!("Unknown option: {}", option.option);
-                write_nbd_option_reply(stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_UNSUP, data: Vec::new()}).await?;
+                write_nbd_option_reply(&mut stream, OptionReply {option: option.option, reply_type: NBD_REP_ERR_UNSUP, data: Vec::new()}).await?;
                 stream.flush().await?;
             }
         }
@@ -197,7 +588,7 @@ struct TransmissionRequest {
     cmdtype: u16,
     handle: u64,
     offset: u64,
-    length: u32,
+    length: u64,
     data: Option<Vec<u8>>
 }
 struct TransmissionSimpleResponse{
@@ -215,19 +606,149 @@ impl TransmissionSimpleResponse{
         }
         Ok(())
     }
+    /// Extended headers have no simple replies, so the reply is sent as a single final chunk instead.
+    /// Simple replies only carry data when structured replies are off, which extended headers rule out.
+    fn into_structured(self)->TransmissionStructuredResponse{
+        let chunk=if self.error==0 {
+            ReplyChunk::None
+        }else{
+            ReplyChunk::Error{error: self.error, message: String::new()}
+        };
+        TransmissionStructuredResponse{flags: NBD_REPLY_FLAG_DONE, handle: self.handle, chunk}
+    }
+    pub async fn send(self, replies: &mut ReplySender)->Result<(), Box<dyn Error>>{
+        replies.send(Reply::Simple(self)).await
+    }
+}
+/// Payload of a single structured reply chunk.
+enum ReplyChunk{
+    None,
+    OffsetData{offset: u64, data: Vec<u8>},
+    OffsetHole{offset: u64, length: u32},
+    BlockStatus{context_id: u32, extents: Vec<(u64, u32)>},
+    Error{error: u32, message: String},
+    ErrorOffset{error: u32, message: String, offset: u64}
+}
+struct TransmissionStructuredResponse{
+    flags: u16,
+    handle: u64,
+    chunk: ReplyChunk
+}
+impl TransmissionStructuredResponse{
+    /// Writes the chunk with a compact header, or with an extended header echoing the request offset.
+    pub async fn write_to<T: AsyncWrite+Unpin>(self, stream: &mut T, extended_offset: Option<u64>)->Result<(), Box<dyn Error>>{
+        let extended=extended_offset.is_some();
+        let (reply_type, length)=match &self.chunk{
+            ReplyChunk::None=>(NBD_REPLY_TYPE_NONE, 0),
+            ReplyChunk::OffsetData{data, ..}=>(NBD_REPLY_TYPE_OFFSET_DATA, 8+data.len()),
+            ReplyChunk::OffsetHole{..}=>(NBD_REPLY_TYPE_OFFSET_HOLE, 12),
+            ReplyChunk::BlockStatus{extents, ..} if extended=>(NBD_REPLY_TYPE_BLOCK_STATUS_EXT, 8+16*extents.len()),
+            ReplyChunk::BlockStatus{extents, ..}=>(NBD_REPLY_TYPE_BLOCK_STATUS, 4+8*extents.len()),
+            ReplyChunk::Error{message, ..}=>(NBD_REPLY_TYPE_ERROR, 6+message.len()),
+            ReplyChunk::ErrorOffset{message, ..}=>(NBD_REPLY_TYPE_ERROR_OFFSET, 14+message.len())
+        };
+        if let Some(offset)=extended_offset{
+            stream.write_u32(NBD_EXTENDED_REPLY_MAGIC).await?;
+            stream.write_u16(self.flags).await?;
+            stream.write_u16(reply_type).await?;
+            stream.write_u64(self.handle).await?;
+            stream.write_u64(offset).await?;
+            stream.write_u64(length as u64).await?;
+        }else{
+            stream.write_u32(NBD_STRUCTURED_REPLY_MAGIC).await?;
+            stream.write_u16(self.flags).await?;
+            stream.write_u16(reply_type).await?;
+            stream.write_u64(self.handle).await?;
+            stream.write_u32(length as u32).await?;
+        }
+        match self.chunk{
+            ReplyChunk::None=>{}
+            ReplyChunk::OffsetData{offset, data}=>{
+                stream.write_u64(offset).await?;
+                stream.write_all(&data).await?;
+            }
+            ReplyChunk::OffsetHole{offset, length}=>{
+                stream.write_u64(offset).await?;
+                stream.write_u32(length).await?;
+            }
+            ReplyChunk::BlockStatus{context_id, extents} if extended=>{
+                stream.write_u32(context_id).await?;
+                stream.write_u32(extents.len() as u32).await?;
+                for (length, flags) in extents{
+                    stream.write_u64(length).await?;
+                    stream.write_u64(flags as u64).await?;
+                }
+            }
+            ReplyChunk::BlockStatus{context_id, extents}=>{
+                stream.write_u32(context_id).await?;
+                for (length, flags) in extents{
+                    stream.write_u32(length as u32).await?;
+                    stream.write_u32(flags).await?;
+                }
+            }
+            ReplyChunk::Error{error, message}=>{
+                stream.write_u32(error).await?;
+                stream.write_u16(message.len() as u16).await?;
+                stream.write_all(message.as_bytes()).await?;
+            }
+            ReplyChunk::ErrorOffset{error, message, offset}=>{
+                stream.write_u32(error).await?;
+                stream.write_u16(message.len() as u16).await?;
+                stream.write_all(message.as_bytes()).await?;
+                stream.write_u64(offset).await?;
+            }
+        }
+        Ok(())
+    }
+    pub async fn send(self, replies: &mut ReplySender)->Result<(), Box<dyn Error>>{
+        replies.send(Reply::Structured(self)).await
+    }
+}
+/// A reply handed from a request task to the connection's writer task.
+enum Reply{
+    Simple(TransmissionSimpleResponse),
+    Structured(TransmissionStructuredResponse)
+}
+impl Reply{
+    /// Writes the reply to a request at `offset`, in the header format negotiated for the connection.
+    pub async fn write_to<T: AsyncWrite+Unpin>(self, stream: &mut T, offset: u64, extended_headers: bool)->Result<(), Box<dyn Error>>{
+        match (self, extended_headers){
+            (Reply::Simple(reply), false)=>reply.write_to(stream).await,
+            (Reply::Simple(reply), true)=>reply.into_structured().write_to(stream, Some(offset)).await,
+            (Reply::Structured(reply), false)=>reply.write_to(stream, None).await,
+            (Reply::Structured(reply), true)=>reply.write_to(stream, Some(offset)).await
+        }
+    }
+}
+/// Queues the replies of a single request for the writer task.
+#[derive(Clone)]
+struct ReplySender{
+    queue: mpsc::Sender<(u64, Reply)>,
+    /// Offset of the request, which extended reply headers echo back.
+    offset: u64
+}
+impl ReplySender{
+    pub async fn send(&mut self, reply: Reply)->Result<(), Box<dyn Error>>{
+        self.queue.send((self.offset, reply)).await.map_err(|_| NBDError::ConnectionClosed)?;
+        Ok(())
+    }
 }
-async fn read_transmission_request<T: AsyncRead+Unpin>(stream: &mut T)->Result<TransmissionRequest, Box<dyn Error>>{
+async fn read_transmission_request<T: AsyncRead+Unpin>(stream: &mut T, extended_headers: bool)->Result<TransmissionRequest, Box<dyn Error>>{
     let magic=stream.read_u32().await?;
-    if magic!=NBD_REQUEST_MAGIC {
+    let expected_magic=if extended_headers {NBD_EXTENDED_REQUEST_MAGIC} else {NBD_REQUEST_MAGIC};
+    if magic!=expected_magic {
         return Err(NBDError::RequestMagicError)?;
     }else{
         let flags=stream.read_u16().await?;
         let cmdtype=stream.read_u16().await?;
         let handle=stream.read_u64().await?;
         let offset=stream.read_u64().await?;
-        let length=stream.read_u32().await?;
-        let data=if cmdtype==NBD_CMD_WRITE{
-            /// TODO: Dangerous when there is not maximum block size negotiation!
+        let length=if extended_headers {stream.read_u64().await?} else {stream.read_u32().await? as u64};
+        let data=if cmdtype==NBD_CMD_WRITE && length as usize>MAX_BLOCK_SIZE{
+            // Rejected later on, but the payload still has to be consumed to keep the stream in sync.
+            tokio::io::copy(&mut (&mut *stream).take(length), &mut tokio::io::sink()).await?;
+            None
+        }else if cmdtype==NBD_CMD_WRITE{
             let mut data:Vec<u8>=Vec::new();
             data.resize(length as usize, unsafe {MaybeUninit::uninit().assume_init()});
             stream.read_exact(&mut data).await?;
@@ -238,85 +759,527 @@ async fn read_transmission_request<T: AsyncRead+Unpin>(stream: &mut T)->Result<T
         Ok(TransmissionRequest{flags, cmdtype, handle, offset, length, data})
     }
 }
-pub async fn handle_packet<T: AsyncRead+AsyncWrite+Unpin>(stream: &mut T, provider: &mut Box<dyn CloudProvider>)->Result<(), Box<dyn Error>>{
-    'mainloop:loop {
-        let req=read_transmission_request(stream).await?;
-        let block_size=provider.block_size();
-        let total_size=provider.total_size();
-        match req.cmdtype{
-            NBD_CMD_READ=>{
-                //println!("NBD_CMD_READ received. offset={} length={}", req.offset, req.length);
-                if !bound_and_align_check(block_size, total_size, req.offset as usize, req.length as usize){
-                    TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.write_to(stream).await?;
+/// Replies to a failed NBD_CMD_READ or NBD_CMD_BLOCK_STATUS, which must not get a simple reply once structured replies are negotiated.
+async fn write_read_error(replies: &mut ReplySender, options: &NegotiatedOptions, handle: u64, error: u32)->Result<(), Box<dyn Error>>{
+    if options.structured_replies{
+        TransmissionStructuredResponse{flags: NBD_REPLY_FLAG_DONE, handle, chunk: ReplyChunk::Error{error, message: String::new()}}.send(replies).await
+    }else{
+        TransmissionSimpleResponse{error, handle, data: None}.send(replies).await
+    }
+}
+/// Streams a read back chunk by chunk, so that only one chunk is buffered at a time.
+/// All-zero chunks are sent as holes.
+async fn structured_read(replies: &mut ReplySender, provider: &dyn SharedProvider, req: &TransmissionRequest)->Result<(), Box<dyn Error>>{
+    let block_size=provider.block_size();
+    let chunk_size=cmp::max(READ_CHUNK_SIZE/block_size*block_size, block_size);
+    let end=req.offset as usize+req.length as usize;
+    let mut offset=req.offset as usize;
+    while offset<end{
+        let length=cmp::min(chunk_size, end-offset);
+        let mut data=vec![0; length];
+        let flags=if offset+length==end {NBD_REPLY_FLAG_DONE} else {0};
+        match provider.read(offset, &mut data).await{
+            Ok(())=>{
+                let chunk=if data.iter().all(|byte| *byte==0){
+                    ReplyChunk::OffsetHole{offset: offset as u64, length: length as u32}
                 }else{
-                    if req.length==0{
-                        TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.write_to(stream).await?;
-                    }else{
-                        /// TODO: Dangerous when there is not maximum block size negotiation!
-                        let mut data:Vec<u8>=Vec::new();
-                        data.resize(req.length as usize, unsafe {MaybeUninit::uninit().assume_init()});
-                        match provider.read(req.offset as usize, &mut data).await {
-                            Ok(())=>{
-                                TransmissionSimpleResponse{error: 0, handle: req.handle, data: Some(data)}.write_to(stream).await?;
-                            }
-                            Err(err)=>{
-                                eprintln!("NBD_CMD_READ error: {:?}", err);
-                                TransmissionSimpleResponse{error: NBD_EIO, handle: req.handle, data: None}.write_to(stream).await?;
-                            }
+                    ReplyChunk::OffsetData{offset: offset as u64, data}
+                };
+                TransmissionStructuredResponse{flags, handle: req.handle, chunk}.send(replies).await?;
+            }
+            Err(err)=>{
+                eprintln!("NBD_CMD_READ error: {:?}", err);
+                TransmissionStructuredResponse{flags: NBD_REPLY_FLAG_DONE, handle: req.handle, chunk: ReplyChunk::ErrorOffset{error: NBD_EIO, message: err.to_string(), offset: offset as u64}}.send(replies).await?;
+                return Ok(());
+            }
+        }
+        offset+=length;
+    }
+    Ok(())
+}
+/// Answers NBD_CMD_BLOCK_STATUS for the base:allocation context.
+async fn block_status(replies: &mut ReplySender, provider: &dyn SharedProvider, req: &TransmissionRequest)->Result<(), Box<dyn Error>>{
+    match provider.block_status(req.offset as usize, req.length as usize).await{
+        Ok(mut extents)=>{
+            if extents.is_empty(){
+                // Claiming data is always safe.
+                extents.push(Extent::data(req.length as usize));
+            }
+            if req.flags&NBD_CMD_FLAG_REQ_ONE>0{
+                extents.truncate(1);
+            }
+            let extents=extents.iter().map(|extent| {
+                let mut flags=0;
+                if extent.hole {flags|=NBD_STATE_HOLE;}
+                if extent.zero {flags|=NBD_STATE_ZERO;}
+                (extent.length as u64, flags)
+            }).collect();
+            TransmissionStructuredResponse{flags: NBD_REPLY_FLAG_DONE, handle: req.handle, chunk: ReplyChunk::BlockStatus{context_id: BASE_ALLOCATION_CONTEXT_ID, extents}}.send(replies).await?;
+        }
+        Err(err)=>{
+            eprintln!("NBD_CMD_BLOCK_STATUS error: {:?}", err);
+            TransmissionStructuredResponse{flags: NBD_REPLY_FLAG_DONE, handle: req.handle, chunk: ReplyChunk::Error{error: NBD_EIO, message: err.to_string()}}.send(replies).await?;
+        }
+    }
+    Ok(())
+}
+/// Answers a request with an error without serving it.
+async fn reject_request(replies: &mut ReplySender, options: &NegotiatedOptions, req: &TransmissionRequest, error: u32)->Result<(), Box<dyn Error>>{
+    if req.cmdtype==NBD_CMD_READ || req.cmdtype==NBD_CMD_BLOCK_STATUS{
+        write_read_error(replies, options, req.handle, error).await
+    }else{
+        TransmissionSimpleResponse{error, handle: req.handle, data: None}.send(replies).await
+    }
+}
+/// Serves a single request, queueing its replies for the writer task.
+async fn handle_request(provider: &dyn SharedProvider, options: &NegotiatedOptions, req: TransmissionRequest, replies: &mut ReplySender)->Result<(), Box<dyn Error>>{
+    let block_size=provider.block_size();
+    let total_size=provider.total_size();
+    let read_only=(options.transmission_flags&NBD_FLAG_READ_ONLY)>0;
+    match req.cmdtype{
+        NBD_CMD_WRITE | NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES | NBD_CMD_RESIZE if read_only=>{
+            println!("Rejected command {} on read-only export", req.cmdtype);
+            TransmissionSimpleResponse{error: NBD_EPERM, handle: req.handle, data: None}.send(replies).await?;
+        }
+        NBD_CMD_READ=>{
+            //println!("NBD_CMD_READ received. offset={} length={}", req.offset, req.length);
+            if req.length as usize>MAX_BLOCK_SIZE{
+                write_read_error(replies, options, req.handle, NBD_EOVERFLOW).await?;
+            }else if !bound_and_align_check(block_size, total_size, req.offset as usize, req.length as usize){
+                write_read_error(replies, options, req.handle, NBD_EINVAL).await?;
+            }else{
+                if req.length==0{
+                    write_read_error(replies, options, req.handle, NBD_EINVAL).await?;
+                }else if options.structured_replies{
+                    structured_read(replies, provider, &req).await?;
+                }else{
+                    let mut data:Vec<u8>=Vec::new();
+                    data.resize(req.length as usize, unsafe {MaybeUninit::uninit().assume_init()});
+                    match provider.read(req.offset as usize, &mut data).await {
+                        Ok(())=>{
+                            TransmissionSimpleResponse{error: 0, handle: req.handle, data: Some(data)}.send(replies).await?;
+                        }
+                        Err(err)=>{
+                            eprintln!("NBD_CMD_READ error: {:?}", err);
+                            TransmissionSimpleResponse{error: NBD_EIO, handle: req.handle, data: None}.send(replies).await?;
                         }
-
                     }
 
                 }
+
             }
-            NBD_CMD_WRITE=>{
-                //println!("NBD_CMD_write received. offset={} length={}", req.offset, req.length);
-                if (req.offset as usize) >= total_size || (req.offset as usize)+(req.length as usize) > total_size{
-                    TransmissionSimpleResponse{error: NBD_ENOSPC, handle: req.handle, data: None}.write_to(stream).await?;
-                }else if (req.offset as usize) % block_size!=0 || (req.length as usize) % block_size!=0{
-                    TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.write_to(stream).await?;
+        }
+        NBD_CMD_WRITE=>{
+            //println!("NBD_CMD_write received. offset={} length={}", req.offset, req.length);
+            if req.length as usize>MAX_BLOCK_SIZE{
+                TransmissionSimpleResponse{error: NBD_EOVERFLOW, handle: req.handle, data: None}.send(replies).await?;
+            }else if (req.offset as usize).checked_add(req.length as usize).is_none(){
+                TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.send(replies).await?;
+            }else if (req.offset as usize) >= total_size || (req.offset as usize)+(req.length as usize) > total_size{
+                TransmissionSimpleResponse{error: NBD_ENOSPC, handle: req.handle, data: None}.send(replies).await?;
+            }else if (req.offset as usize) % block_size!=0 || (req.length as usize) % block_size!=0{
+                TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.send(replies).await?;
+            }else{
+                let fua_write_through=(req.flags&NBD_CMD_FLAG_FUA) >0;
+                if req.length==0{
+                    TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.send(replies).await?;
                 }else{
-                    let fua_write_through=(req.flags&NBD_CMD_FLAG_FUA) >0;
-                    if req.length==0{
-                        TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.write_to(stream).await?;
-                    }else{
-                        println!("Write offset={} len={} fua={}", req.offset, req.length, fua_write_through);
-                        match provider.write(req.offset as usize, req.data.as_ref().unwrap(), fua_write_through).await{
-                            Ok(())=>{
-                                TransmissionSimpleResponse{error: 0, handle: req.handle, data: None}.write_to(stream).await?;
-                            }
-                            Err(err)=>{
-                                eprintln!("NBD_CMD_WRITE error: {:?}", err);
-                                TransmissionSimpleResponse{error: NBD_EIO, handle: req.handle, data: None}.write_to(stream).await?;
-                            }
+                    println!("Write offset={} len={} fua={}", req.offset, req.length, fua_write_through);
+                    match provider.write(req.offset as usize, req.data.as_ref().unwrap(), fua_write_through).await{
+                        Ok(())=>{
+                            TransmissionSimpleResponse{error: 0, handle: req.handle, data: None}.send(replies).await?;
+                        }
+                        Err(err)=>{
+                            eprintln!("NBD_CMD_WRITE error: {:?}", err);
+                            TransmissionSimpleResponse{error: NBD_EIO, handle: req.handle, data: None}.send(replies).await?;
                         }
                     }
                 }
             }
-            NBD_CMD_DISC=>{
-                println!("NBD_CMD_DISC received.");
-                TransmissionSimpleResponse{error: 0, handle: req.handle, data: None}.write_to(stream).await?;
-                stream.flush().await?;
-                break 'mainloop Ok(());
+        }
+        NBD_CMD_CACHE=>{
+            println!("Cache offset={} len={}", req.offset, req.length);
+            if req.length==0 || !bound_and_align_check(block_size, total_size, req.offset as usize, req.length as usize){
+                TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.send(replies).await?;
+            }else{
+                match provider.prefetch(req.offset as usize, req.length as usize).await{
+                    Ok(())=>{
+                        TransmissionSimpleResponse{error: 0, handle: req.handle, data: None}.send(replies).await?;
+                    }
+                    Err(err)=>{
+                        eprintln!("NBD_CMD_CACHE error: {:?}", err);
+                        TransmissionSimpleResponse{error: NBD_EIO, handle: req.handle, data: None}.send(replies).await?;
+                    }
+                }
             }
-            NBD_CMD_FLUSH=>{
-                println!("NBD_CMD_FLUSH received.");
-                match provider.flush().await{
+        }
+        NBD_CMD_TRIM=>{
+            println!("Trim offset={} len={}", req.offset, req.length);
+            if req.length==0 || !bound_and_align_check(block_size, total_size, req.offset as usize, req.length as usize){
+                TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.send(replies).await?;
+            }else{
+                let mut result=provider.discard(req.offset as usize, req.length as usize).await;
+                if result.is_ok() && (req.flags&NBD_CMD_FLAG_FUA)>0{
+                    result=provider.flush().await;
+                }
+                match result{
                     Ok(())=>{
-                        TransmissionSimpleResponse{error: 0, handle: req.handle, data: None}.write_to(stream).await?;
+                        TransmissionSimpleResponse{error: 0, handle: req.handle, data: None}.send(replies).await?;
                     }
                     Err(err)=>{
-                        eprintln!("NBD_CMD_FLUSH error: {:?}", err);
-                        TransmissionSimpleResponse{error: NBD_EIO, handle: req.handle, data: None}.write_to(stream).await?;
+                        eprintln!("NBD_CMD_TRIM error: {:?}", err);
+                        TransmissionSimpleResponse{error: NBD_EIO, handle: req.handle, data: None}.send(replies).await?;
                     }
                 }
             }
-            _=>{
-                println!("Unknown command: {}", req.cmdtype);
-                TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.write_to(stream).await?;
+        }
+        NBD_CMD_WRITE_ZEROES=>{
+            let fua_write_through=(req.flags&NBD_CMD_FLAG_FUA)>0;
+            let may_trim=(req.flags&NBD_CMD_FLAG_NO_HOLE)==0;
+            let fast_only=(req.flags&NBD_CMD_FLAG_FAST_ZERO)>0;
+            println!("Write zeroes offset={} len={} fua={} may_trim={} fast_only={}", req.offset, req.length, fua_write_through, may_trim, fast_only);
+            // Extended headers allow lengths that wrap around when added to the offset.
+            if (req.offset as usize).checked_add(req.length as usize).is_none(){
+                TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.send(replies).await?;
+            }else if (req.offset as usize) >= total_size || (req.offset as usize)+(req.length as usize) > total_size{
+                TransmissionSimpleResponse{error: NBD_ENOSPC, handle: req.handle, data: None}.send(replies).await?;
+            }else if req.length==0 || (req.offset as usize) % block_size!=0 || (req.length as usize) % block_size!=0{
+                TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.send(replies).await?;
+            }else{
+                match provider.write_zeroes(req.offset as usize, req.length as usize, may_trim, fast_only, fua_write_through).await{
+                    Ok(())=>{
+                        TransmissionSimpleResponse{error: 0, handle: req.handle, data: None}.send(replies).await?;
+                    }
+                    Err(err) if err.kind()==std::io::ErrorKind::Unsupported=>{
+                        TransmissionSimpleResponse{error: NBD_ENOTSUP, handle: req.handle, data: None}.send(replies).await?;
+                    }
+                    Err(err)=>{
+                        eprintln!("NBD_CMD_WRITE_ZEROES error: {:?}", err);
+                        TransmissionSimpleResponse{error: NBD_EIO, handle: req.handle, data: None}.send(replies).await?;
+                    }
+                }
+            }
+        }
+        NBD_CMD_RESIZE=>{
+            // The new size travels in the offset field.
+            println!("Resize to {}", req.offset);
+            if !provider.capabilities().resize || req.length!=0{
+                TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.send(replies).await?;
+            }else{
+                match provider.resize(req.offset as usize).await{
+                    Ok(())=>{
+                        TransmissionSimpleResponse{error: 0, handle: req.handle, data: None}.send(replies).await?;
+                    }
+                    Err(err) if err.kind()==std::io::ErrorKind::InvalidInput=>{
+                        TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.send(replies).await?;
+                    }
+                    Err(err)=>{
+                        eprintln!("NBD_CMD_RESIZE error: {:?}", err);
+                        TransmissionSimpleResponse{error: NBD_EIO, handle: req.handle, data: None}.send(replies).await?;
+                    }
+                }
+            }
+        }
+        NBD_CMD_BLOCK_STATUS=>{
+            if !options.meta_contexts.contains(&BASE_ALLOCATION_CONTEXT_ID){
+                write_read_error(replies, options, req.handle, NBD_EINVAL).await?;
+            }else if req.length==0 || !bound_and_align_check(block_size, total_size, req.offset as usize, req.length as usize){
+                write_read_error(replies, options, req.handle, NBD_EINVAL).await?;
+            }else{
+                block_status(replies, provider, &req).await?;
+            }
+        }
+        NBD_CMD_FLUSH=>{
+            println!("NBD_CMD_FLUSH received.");
+            match provider.flush().await{
+                Ok(())=>{
+                    TransmissionSimpleResponse{error: 0, handle: req.handle, data: None}.send(replies).await?;
+                }
+                Err(err)=>{
+                    eprintln!("NBD_CMD_FLUSH error: {:?}", err);
+                    TransmissionSimpleResponse{error: NBD_EIO, handle: req.handle, data: None}.send(replies).await?;
+                }
+            }
+        }
+        _=>{
+            println!("Unknown command: {}", req.cmdtype);
+            TransmissionSimpleResponse{error: NBD_EINVAL, handle: req.handle, data: None}.send(replies).await?;
+        }
+    }
+    Ok(())
+}
+/// Resolves once the server starts shutting down, i.e. once `true` is sent on the channel or its sender is gone.
+pub async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>){
+    while let Some(shutting_down)=shutdown.recv().await{
+        if shutting_down{
+            return;
+        }
+    }
+}
+/// Runs the transmission phase of a connection.
+/// Requests are read by a reader task and dispatched to concurrent tasks, at most `max_in_flight` at a time,
+/// while a writer task sends their replies back in whatever order they complete.
+/// Serves requests until the client disconnects or the server shuts down.
+/// Once the server starts shutting down, new requests are answered with NBD_ESHUTDOWN until the client
+/// disconnects or `SHUTDOWN_TIMEOUT` passes. Either way the requests already read are answered and
+/// the export is flushed before returning, unless the client stops taking replies for `DRAIN_TIMEOUT`.
+pub async fn handle_packet<T: AsyncRead+AsyncWrite+Send+'static>(stream: T, provider: Arc<dyn SharedProvider>, options: NegotiatedOptions, max_in_flight: usize, mut shutdown: watch::Receiver<bool>)->Result<(), Box<dyn Error>>{
+    let (mut reader, mut writer)=tokio::io::split(stream);
+    let (replies, mut queue)=mpsc::channel::<(u64, Reply)>(max_in_flight);
+    // Dropped once the connection is done with, which stops the reader and writer tasks wherever they are.
+    let (close, closed)=watch::channel(false);
+    let extended_headers=options.extended_headers;
+    let mut writer_closed=closed.clone();
+    let mut writer_task=tokio::spawn(async move {
+        let write_replies=async {
+            while let Some((offset, reply))=queue.recv().await{
+                let written=reply.write_to(&mut writer, offset, extended_headers).await.is_ok() && writer.flush().await.is_ok();
+                if !written{
+                    eprintln!("Failed to send reply, closing connection.");
+                    break;
+                }
             }
+        };
+        tokio::select!{
+            _=write_replies=>{},
+            _=wait_for_shutdown(&mut writer_closed)=>{}
         }
-        stream.flush().await?;
+    });
+    let options=Arc::new(options);
+    let in_flight=Arc::new(Semaphore::new(max_in_flight));
+    // Requests are read by a task of their own, so that nothing is lost when the loop below stops waiting for one.
+    // Every request but NBD_CMD_DISC comes with a permit of `in_flight`, released once it is answered.
+    let (requests_sender, mut requests)=mpsc::channel::<TransmissionRequest>(1);
+    let (reader_in_flight, mut reader_closed)=(Arc::clone(&in_flight), closed.clone());
+    tokio::spawn(async move {
+        let mut requests_sender=requests_sender;
+        let read_requests=async {
+            loop {
+                let req=match read_transmission_request(&mut reader, extended_headers).await{
+                    Ok(req)=>req,
+                    Err(err)=>{
+                        eprintln!("Failed to read request, closing connection: {}", err);
+                        break;
+                    }
+                };
+                let disconnect=req.cmdtype==NBD_CMD_DISC;
+                if !disconnect{
+                    reader_in_flight.acquire().await.forget();
+                }
+                if requests_sender.send(req).await.is_err(){
+                    if !disconnect{
+                        reader_in_flight.add_permits(1);
+                    }
+                    break;
+                }
+                if disconnect{
+                    break;
+                }
+            }
+        };
+        tokio::select!{
+            _=read_requests=>{},
+            _=wait_for_shutdown(&mut reader_closed)=>{}
+        }
+    });
+    let mut deadline: Option<tokio::time::Instant>=None;
+    loop {
+        let req=tokio::select!{
+            req=requests.recv()=>match req{
+                Some(req)=>req,
+                None=>break
+            },
+            _=wait_for_shutdown(&mut shutdown), if deadline.is_none()=>{
+                println!("Shutting down, answering new requests with NBD_ESHUTDOWN.");
+                deadline=Some(tokio::time::Instant::now()+SHUTDOWN_TIMEOUT);
+                continue;
+            }
+            _=tokio::time::delay_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some()=>{
+                println!("Client did not disconnect in time, closing connection.");
+                break;
+            }
+        };
+        if req.cmdtype==NBD_CMD_DISC{
+            // The client expects no reply.
+            println!("NBD_CMD_DISC received.");
+            break;
+        }
+        let shutting_down=deadline.is_some();
+        let mut replies=ReplySender{queue: replies.clone(), offset: req.offset};
+        let (provider, options, in_flight)=(Arc::clone(&provider), Arc::clone(&options), Arc::clone(&in_flight));
+        tokio::spawn(async move {
+            let result=if shutting_down{
+                reject_request(&mut replies, &options, &req, NBD_ESHUTDOWN).await
+            }else{
+                handle_request(provider.as_ref(), &options, req, &mut replies).await
+            };
+            if let Err(err)=result{
+                eprintln!("Request failed: {}", err);
+            }
+            in_flight.add_permits(1);
+        });
+    }
+    // Stops the reader from taking more permits. A request it already passed on still holds one,
+    // so it is answered rather than dropped.
+    requests.close();
+    while let Some(req)=requests.recv().await{
+        if req.cmdtype==NBD_CMD_DISC{
+            continue;
+        }
+        let mut replies=ReplySender{queue: replies.clone(), offset: req.offset};
+        let (options, in_flight)=(Arc::clone(&options), Arc::clone(&in_flight));
+        tokio::spawn(async move {
+            if let Err(err)=reject_request(&mut replies, &options, &req, NBD_ESHUTDOWN).await{
+                eprintln!("Request failed: {}", err);
+            }
+            in_flight.add_permits(1);
+        });
+    }
+    // Wait for every request in flight before tearing the connection down.
+    let drained=tokio::time::timeout(DRAIN_TIMEOUT, async {
+        for _ in 0..max_in_flight{
+            in_flight.acquire().await.forget();
+        }
+    }).await.is_ok();
+    if !drained{
+        eprintln!("Requests still in flight after {:?}, closing connection.", DRAIN_TIMEOUT);
+    }
+    // Other connections may keep using the export, but nothing written on this one should linger in caches.
+    if let Err(err)=provider.flush().await{
+        eprintln!("Flush on disconnect failed: {:?}", err);
     }
+    drop(replies);
+    if tokio::time::timeout(DRAIN_TIMEOUT, &mut writer_task).await.is_err(){
+        eprintln!("Client stopped taking replies, closing connection.");
+    }
+    drop(close);
+    Ok(())
 }
 
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn info_payload(name: &str, requests: &[u16])->Vec<u8>{
+        let mut data=Vec::new();
+        data.put_u32(name.len() as u32);
+        data.put_slice(name.as_bytes());
+        data.put_u16(requests.len() as u16);
+        for request in requests.iter(){
+            data.put_u16(*request);
+        }
+        data
+    }
+
+    #[test]
+    fn parses_info_requests(){
+        let request=parse_info_request(&info_payload("disk", &[NBD_INFO_NAME, NBD_INFO_BLOCK_SIZE])).unwrap();
+        assert_eq!(request.name, "disk");
+        assert_eq!(request.requests, [NBD_INFO_NAME, NBD_INFO_BLOCK_SIZE]);
+        // The default export has an empty name.
+        let request=parse_info_request(&info_payload("", &[])).unwrap();
+        assert_eq!(request.name, "");
+        assert!(request.requests.is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_info_requests(){
+        let data=info_payload("disk", &[NBD_INFO_DESCRIPTION]);
+        for length in 0..data.len(){
+            assert!(parse_info_request(&data[..length]).is_none(), "{}", length);
+        }
+        let mut data=info_payload("disk", &[]);
+        data.put_u16(NBD_INFO_NAME);
+        assert!(parse_info_request(&data).is_none());
+        // The name length runs past the end.
+        let mut data=Vec::new();
+        data.put_u32(u32::MAX);
+        data.put_u16(0);
+        assert!(parse_info_request(&data).is_none());
+        // Names must be UTF-8.
+        let mut data=info_payload("ab", &[]);
+        data[4]=0xff;
+        assert!(parse_info_request(&data).is_none());
+    }
+
+    fn meta_context_payload(name: &str, queries: &[&str])->Vec<u8>{
+        let mut data=Vec::new();
+        data.put_u32(name.len() as u32);
+        data.put_slice(name.as_bytes());
+        data.put_u32(queries.len() as u32);
+        for query in queries.iter(){
+            data.put_u32(query.len() as u32);
+            data.put_slice(query.as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn parses_meta_context_requests(){
+        let request=parse_meta_context_request(&meta_context_payload("disk", &["base:allocation", "qemu:"])).unwrap();
+        assert_eq!(request.name, "disk");
+        assert_eq!(request.queries, ["base:allocation", "qemu:"]);
+        let request=parse_meta_context_request(&meta_context_payload("", &[])).unwrap();
+        assert!(request.queries.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_meta_context_requests(){
+        let data=meta_context_payload("disk", &["base:allocation"]);
+        for length in 0..data.len(){
+            assert!(parse_meta_context_request(&data[..length]).is_none(), "{}", length);
+        }
+        let mut data=meta_context_payload("disk", &[]);
+        data.put_u8(0);
+        assert!(parse_meta_context_request(&data).is_none());
+        // More queries announced than sent.
+        let mut data=meta_context_payload("disk", &["base:allocation"]);
+        data[11]=2;
+        assert!(parse_meta_context_request(&data).is_none());
+    }
+
+    fn matched(queries: &[&str], list: bool)->Vec<&'static str>{
+        let request=MetaContextRequest{name: String::new(), queries: queries.iter().map(|query| String::from(*query)).collect()};
+        match_meta_contexts(&request, list).into_iter().map(|(_, name)| name).collect()
+    }
+
+    #[test]
+    fn matches_meta_contexts(){
+        assert_eq!(matched(&[], true), ["base:allocation"]);
+        assert!(matched(&[], false).is_empty());
+        assert_eq!(matched(&["base:allocation"], false), ["base:allocation"]);
+        assert_eq!(matched(&["base:allocation", "base:allocation"], false), ["base:allocation"]);
+        // Namespaces only match when listing.
+        assert_eq!(matched(&["base:"], true), ["base:allocation"]);
+        assert!(matched(&["base:"], false).is_empty());
+        assert!(matched(&["qemu:dirty-bitmap:x", "base"], true).is_empty());
+        assert_eq!(match_meta_contexts(&MetaContextRequest{name: String::new(), queries: vec![String::from("base:allocation")]}, false), [(BASE_ALLOCATION_CONTEXT_ID, "base:allocation")]);
+    }
+
+    /// Serves a single request on an extended-headers connection to a 1 MiB export, returning the error it is answered with.
+    async fn request_error(cmdtype: u16, offset: u64, length: u64)->u32{
+        let provider=LockedProvider::new(crate::support::MemoryProvider::new(1<<20));
+        let options=NegotiatedOptions{structured_replies: true, extended_headers: true, meta_contexts: vec![BASE_ALLOCATION_CONTEXT_ID], ..NegotiatedOptions::default()};
+        let (queue, mut replies)=mpsc::channel(16);
+        let req=TransmissionRequest{flags: 0, cmdtype, handle: 1, offset, length, data: None};
+        handle_request(&provider, &options, req, &mut ReplySender{queue, offset}).await.unwrap();
+        match replies.recv().await.unwrap().1{
+            Reply::Simple(reply)=>reply.error,
+            Reply::Structured(TransmissionStructuredResponse{chunk: ReplyChunk::Error{error, ..}, ..})=>error,
+            Reply::Structured(_)=>0
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_ranges_that_overflow(){
+        let length=0u64.wrapping_sub(4096);
+        for cmdtype in [NBD_CMD_TRIM, NBD_CMD_CACHE, NBD_CMD_WRITE_ZEROES, NBD_CMD_BLOCK_STATUS].iter(){
+            assert_eq!(request_error(*cmdtype, 4096, length).await, NBD_EINVAL, "command {}", cmdtype);
+            assert_eq!(request_error(*cmdtype, 4096, 4096).await, 0, "command {}", cmdtype);
+        }
+        assert_eq!(request_error(NBD_CMD_WRITE_ZEROES, 1<<20, 4096).await, NBD_ENOSPC);
+        assert_eq!(request_error(NBD_CMD_READ, 4096, length).await, NBD_EOVERFLOW);
+        assert_eq!(request_error(NBD_CMD_WRITE, length, 8192).await, NBD_EINVAL);
+    }
+}
@@ -1,5 +1,6 @@
-use super::{CloudProvider, CloudProviderExt};
-use std::io::Write;
+use super::{Capabilities, CloudProvider, CloudProviderExt, Extent, push_extent};
+use super::registry::{LayerFactory, ProviderSpec, Registry};
+use std::io::{ErrorKind, Write};
 use std::ops::Range;
 use std::cmp::{max, min};
 use std::slice::SliceIndex;
@@ -64,6 +65,96 @@ impl<T: CloudProvider> CloudProvider for ByteGranularityProvider<T> {
         Ok(())
     }
 
+    async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        let block_size=self.underlying_block_size();
+        let range=self.underlying_block_range(offset, buf.len());
+        let mut buffer=self.provider.create_block_buffer();
+        for (block_id, range_block, range_local) in range.iter(){
+            if range_local.end-range_local.start == block_size{
+                self.provider.unsafe_read_block_shared(*block_id, &mut buf[Range::clone(range_local)]).await?;
+            }else{
+                self.provider.unsafe_read_block_shared(*block_id, &mut buffer).await?;
+                buf[Range::clone(range_local)].copy_from_slice(&buffer[Range::clone(range_block)]);
+            }
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_prefetch(&mut self, offset: usize, size: usize) -> Result<(), std::io::Error> {
+        let block_size=self.underlying_block_size();
+        let start=offset/block_size*block_size;
+        let end=min((offset+size+block_size-1)/block_size*block_size, self.provider.total_size());
+        self.provider.unsafe_prefetch(start, end-start).await
+    }
+
+    async unsafe fn unsafe_discard(&mut self, offset: usize, size: usize) -> Result<(), std::io::Error> {
+        // Only blocks that are discarded as a whole can be passed down.
+        let block_size=self.underlying_block_size();
+        let start=(offset+block_size-1)/block_size*block_size;
+        let end=(offset+size)/block_size*block_size;
+        if start<end{
+            self.provider.unsafe_discard(start, end-start).await
+        }else{
+            Ok(())
+        }
+    }
+
+    async unsafe fn unsafe_write_zeroes(&mut self, offset: usize, size: usize, may_trim: bool, fast_only: bool, write_through: bool) -> Result<(), std::io::Error> {
+        // Whole blocks are zeroed by the underlying provider, partial ones by read-and-write-back.
+        let block_size=self.underlying_block_size();
+        let start=(offset+block_size-1)/block_size*block_size;
+        let end=(offset+size)/block_size*block_size;
+        if fast_only && (start!=offset || end!=offset+size){
+            // Partial blocks take a read-and-write-back, which is never fast.
+            return Err(ErrorKind::Unsupported)?;
+        }
+        if start<end{
+            self.provider.unsafe_write_zeroes(start, end-start, may_trim, fast_only, write_through).await?;
+            if offset<start{
+                self.unsafe_write(offset, &vec![0; start-offset], write_through).await?;
+            }
+            if end<offset+size{
+                self.unsafe_write(end, &vec![0; offset+size-end], write_through).await?;
+            }
+            Ok(())
+        }else{
+            self.unsafe_write(offset, &vec![0; size], write_through).await
+        }
+    }
+
+    async unsafe fn unsafe_block_status(&mut self, offset: usize, size: usize) -> Result<Vec<Extent>, std::io::Error> {
+        // Widen the range to whole underlying blocks, then cut the answer back to the requested range.
+        let block_size=self.underlying_block_size();
+        let start=offset/block_size*block_size;
+        let end=min((offset+size+block_size-1)/block_size*block_size, self.provider.total_size());
+        let underlying=self.provider.unsafe_block_status(start, end-start).await?;
+        let mut skip=offset-start;
+        let mut remaining=size;
+        let mut extents=Vec::new();
+        for mut extent in underlying{
+            if extent.length<=skip{
+                skip-=extent.length;
+                continue;
+            }
+            extent.length=min(extent.length-skip, remaining);
+            skip=0;
+            remaining-=extent.length;
+            push_extent(&mut extents, extent);
+            if remaining==0{
+                break;
+            }
+        }
+        Ok(extents)
+    }
+
+    async fn resize(&mut self, size: usize) -> Result<(), std::io::Error> {
+        self.provider.resize(size).await
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.provider.capabilities()
+    }
+
     async fn flush(&mut self) -> Result<(), std::io::Error> {
         self.provider.flush().await
     }
@@ -71,4 +162,14 @@ impl<T: CloudProvider> CloudProvider for ByteGranularityProvider<T> {
     fn block_size(&self) -> usize {
         1
     }
-}
\ No newline at end of file
+}
+struct ByteGranularityFactory;
+impl LayerFactory for ByteGranularityFactory{
+    fn wrap(&self, _spec: &ProviderSpec, provider: Box<dyn CloudProvider>) -> Result<Box<dyn CloudProvider>, Box<dyn std::error::Error>> {
+        Ok(Box::new(ByteGranularityProvider::new(provider)))
+    }
+}
+/// Registers the `bytes` layer.
+pub fn register(registry: &mut Registry){
+    registry.register_layer("bytes", ByteGranularityFactory);
+}
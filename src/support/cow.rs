@@ -0,0 +1,181 @@
+use super::{Capabilities, CloudProvider, CloudProviderExt, Extent, push_extent};
+use super::registry::{LayerFactory, ProviderSpec, Registry};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use async_trait::async_trait;
+/// Keeps every write in memory, leaving the wrapped provider untouched, e.g. to run a VM off a read-only image.
+/// Written blocks are lost once the server stops.
+pub struct CowProvider<T: CloudProvider>{
+    provider: T,
+    /// Blocks written since the start, where `None` stands for a block of zeroes.
+    overlay: BTreeMap<usize, Option<Vec<u8>>>
+}
+impl<T: CloudProvider> CowProvider<T>{
+    pub fn new(provider: T)->Self{
+        CowProvider{provider, overlay: BTreeMap::new()}
+    }
+}
+#[async_trait]
+impl<T: CloudProvider> CloudProvider for CowProvider<T>{
+    fn total_size(&self) -> usize {
+        self.provider.total_size()
+    }
+
+    async unsafe fn unsafe_write(&mut self, offset: usize, buf: &[u8], _write_through: bool) -> std::io::Result<()> {
+        for (block_id, _range_block, range_local) in self.block_range(offset, buf.len()).iter(){
+            self.overlay.insert(*block_id, Some(buf[Range::clone(range_local)].to_vec()));
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_read(&mut self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        for (block_id, _range_block, range_local) in self.block_range(offset, buf.len()).iter(){
+            match self.overlay.get(block_id){
+                Some(Some(data))=>buf[Range::clone(range_local)].copy_from_slice(data),
+                Some(None)=>{
+                    for byte in buf[Range::clone(range_local)].iter_mut(){
+                        *byte=0;
+                    }
+                }
+                None=>self.provider.unsafe_read_block(*block_id, &mut buf[Range::clone(range_local)]).await?
+            }
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        for (block_id, _range_block, range_local) in self.block_range(offset, buf.len()).iter(){
+            match self.overlay.get(block_id){
+                Some(Some(data))=>buf[Range::clone(range_local)].copy_from_slice(data),
+                Some(None)=>{
+                    for byte in buf[Range::clone(range_local)].iter_mut(){
+                        *byte=0;
+                    }
+                }
+                None=>self.provider.unsafe_read_block_shared(*block_id, &mut buf[Range::clone(range_local)]).await?
+            }
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_prefetch(&mut self, offset: usize, size: usize) -> std::io::Result<()> {
+        self.provider.unsafe_prefetch(offset, size).await
+    }
+
+    async unsafe fn unsafe_discard(&mut self, offset: usize, size: usize) -> std::io::Result<()> {
+        // Discarded blocks are only dropped from memory, so they read from the wrapped provider again.
+        for (block_id, _range_block, _range_local) in self.block_range(offset, size).iter(){
+            self.overlay.remove(block_id);
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_write_zeroes(&mut self, offset: usize, size: usize, _may_trim: bool, _fast_only: bool, _write_through: bool) -> std::io::Result<()> {
+        for (block_id, _range_block, _range_local) in self.block_range(offset, size).iter(){
+            self.overlay.insert(*block_id, None);
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_block_status(&mut self, offset: usize, size: usize) -> std::io::Result<Vec<Extent>> {
+        let block_size=self.block_size();
+        let mut extents=Vec::new();
+        let mut position=offset;
+        while position<offset+size{
+            let block_id=position/block_size;
+            match self.overlay.get(&block_id){
+                Some(Some(_))=>push_extent(&mut extents, Extent::data(block_size)),
+                Some(None)=>push_extent(&mut extents, Extent{length: block_size, hole: false, zero: true}),
+                None=>{
+                    // Blocks untouched since the start are reported by the wrapped provider, as far as it gets.
+                    let end=self.overlay.range(block_id..).next().map(|(block_id, _)| block_id*block_size).unwrap_or(offset+size).min(offset+size);
+                    let underlying=self.provider.unsafe_block_status(position, end-position).await?;
+                    let length: usize=underlying.iter().map(|extent| extent.length).sum();
+                    for extent in underlying{
+                        push_extent(&mut extents, extent);
+                    }
+                    if position+length<end{
+                        break;
+                    }
+                    position=end;
+                    continue;
+                }
+            }
+            position+=block_size;
+        }
+        Ok(extents)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let underlying=self.provider.capabilities();
+        Capabilities{trim: true, rotational: underlying.rotational, shared_read: underlying.shared_read, ..Capabilities::default()}
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn block_size(&self) -> usize {
+        self.provider.block_size()
+    }
+}
+struct CowFactory;
+impl LayerFactory for CowFactory{
+    fn wrap(&self, _spec: &ProviderSpec, provider: Box<dyn CloudProvider>) -> Result<Box<dyn CloudProvider>, Box<dyn std::error::Error>> {
+        Ok(Box::new(CowProvider::new(provider)))
+    }
+}
+/// Registers the `cow` layer.
+pub fn register(registry: &mut Registry){
+    registry.register_layer("cow", CowFactory);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::support::MemoryProvider;
+
+    const BLOCK: usize=crate::nbd::PREFERRED_BLOCK_SIZE;
+
+    async fn image()->CowProvider<MemoryProvider>{
+        let mut memory=MemoryProvider::new(4*BLOCK);
+        memory.write(0, &vec![1; 4*BLOCK], false).await.unwrap();
+        CowProvider::new(memory)
+    }
+
+    #[tokio::test]
+    async fn keeps_writes_in_memory(){
+        let mut cow=image().await;
+        cow.write(BLOCK, &vec![2; BLOCK], false).await.unwrap();
+        cow.write_zeroes(2*BLOCK, BLOCK, false, false, false).await.unwrap();
+        let mut buf=vec![0; 4*BLOCK];
+        cow.read(0, &mut buf).await.unwrap();
+        assert!(buf[..BLOCK].iter().all(|byte| *byte==1));
+        assert!(buf[BLOCK..2*BLOCK].iter().all(|byte| *byte==2));
+        assert!(buf[2*BLOCK..3*BLOCK].iter().all(|byte| *byte==0));
+        assert!(buf[3*BLOCK..].iter().all(|byte| *byte==1));
+        cow.provider.read(0, &mut buf).await.unwrap();
+        assert!(buf.iter().all(|byte| *byte==1));
+    }
+
+    #[tokio::test]
+    async fn discard_brings_back_the_image(){
+        let mut cow=image().await;
+        cow.write(0, &vec![2; 2*BLOCK], false).await.unwrap();
+        cow.discard(0, BLOCK).await.unwrap();
+        let mut buf=vec![0; 2*BLOCK];
+        cow.read(0, &mut buf).await.unwrap();
+        assert!(buf[..BLOCK].iter().all(|byte| *byte==1));
+        assert!(buf[BLOCK..].iter().all(|byte| *byte==2));
+    }
+
+    #[tokio::test]
+    async fn reports_written_blocks(){
+        let mut cow=image().await;
+        cow.write_zeroes(BLOCK, BLOCK, true, false, false).await.unwrap();
+        cow.write(2*BLOCK, &vec![2; BLOCK], false).await.unwrap();
+        let zero=Extent{length: BLOCK, hole: false, zero: true};
+        assert_eq!(cow.block_status(0, 4*BLOCK).await.unwrap(), [Extent::data(BLOCK), zero, Extent::data(2*BLOCK)]);
+        assert_eq!(cow.block_status(BLOCK, BLOCK).await.unwrap(), [zero]);
+    }
+}
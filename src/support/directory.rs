@@ -0,0 +1,303 @@
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+use async_trait::async_trait;
+use super::{Capabilities, CloudProvider, CloudProviderExt, Extent, push_extent};
+use super::registry::{BackendFactory, ProviderSpec, Registry};
+
+/// Stores every block as `{id}.block` in a local directory, the same layout SeafileProvider uses in a library.
+/// Missing blocks read as zeroes, and blocks are replaced atomically by writing a temporary file and renaming it.
+pub struct DirectoryProvider{
+    root: PathBuf,
+    total_size: usize,
+    block_size: usize,
+    /// Blocks present in the directory.
+    allocated_blocks: BTreeSet<usize>,
+    /// Blocks written since the last flush, which may not have reached the disk yet.
+    unsynced_blocks: BTreeSet<usize>
+}
+
+impl DirectoryProvider{
+    /// Opens a directory, creating it if missing. Use `seafile::BLOCK_SIZE` as block size to keep volumes
+    /// interchangeable with Seafile libraries.
+    pub fn open(path: &str, total_size: usize, block_size: usize)->std::io::Result<DirectoryProvider>{
+        if block_size==0 || total_size==0 || total_size % block_size!=0{
+            return Err(ErrorKind::InvalidInput)?;
+        }
+        std::fs::create_dir_all(path)?;
+        let mut allocated_blocks=BTreeSet::new();
+        for entry in std::fs::read_dir(path)?{
+            let name=entry?.file_name();
+            if let Some(block_id)=name.to_str().and_then(|name| name.strip_suffix(".block")).and_then(|id| id.parse::<usize>().ok()){
+                allocated_blocks.insert(block_id);
+            }
+        }
+        Ok(DirectoryProvider{
+            root: PathBuf::from(path),
+            total_size,
+            block_size,
+            allocated_blocks,
+            unsynced_blocks: BTreeSet::new()
+        })
+    }
+    fn block_path(&self, block_id: usize)->PathBuf{
+        self.root.join(format!("{}.block", block_id))
+    }
+    fn get_block(&self, block_id: usize, buf: &mut [u8])->std::io::Result<()>{
+        if !self.allocated_blocks.contains(&block_id){
+            // Missing blocks read as zeroes.
+            for byte in buf.iter_mut(){
+                *byte=0;
+            }
+            return Ok(());
+        }
+        let mut file=File::open(self.block_path(block_id))?;
+        let mut length=0;
+        while length<buf.len(){
+            match file.read(&mut buf[length..])?{
+                0=>break,
+                read=>length+=read
+            }
+        }
+        // Short blocks are padded with zeroes.
+        for byte in buf[length..].iter_mut(){
+            *byte=0;
+        }
+        Ok(())
+    }
+    fn put_block(&mut self, block_id: usize, buf: &[u8], write_through: bool)->std::io::Result<()>{
+        let path=self.block_path(block_id);
+        let temp_path=self.root.join(format!("{}.block.tmp", block_id));
+        let mut file=OpenOptions::new().write(true).create(true).truncate(true).open(&temp_path)?;
+        file.write_all(buf)?;
+        if write_through{
+            file.sync_data()?;
+        }
+        std::fs::rename(&temp_path, &path)?;
+        self.allocated_blocks.insert(block_id);
+        if write_through{
+            self.sync_root()?;
+        }else{
+            self.unsynced_blocks.insert(block_id);
+        }
+        Ok(())
+    }
+    fn delete_block(&mut self, block_id: usize)->std::io::Result<()>{
+        match std::fs::remove_file(self.block_path(block_id)){
+            Err(err) if err.kind()!=ErrorKind::NotFound=>return Err(err),
+            _=>{}
+        }
+        self.allocated_blocks.remove(&block_id);
+        self.unsynced_blocks.remove(&block_id);
+        Ok(())
+    }
+    /// Blocks present in the directory that overlap a range.
+    fn allocated_range(&self, offset: usize, size: usize)->impl Iterator<Item=&usize>{
+        self.allocated_blocks.range(offset/self.block_size..=(offset+size-1)/self.block_size)
+    }
+    /// Makes renames and deletions in the directory durable.
+    fn sync_root(&self)->std::io::Result<()>{
+        File::open(&self.root)?.sync_all()
+    }
+}
+#[async_trait]
+impl CloudProvider for DirectoryProvider {
+    fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    async unsafe fn unsafe_write(&mut self, offset: usize, buf: &[u8], write_through: bool) -> std::io::Result<()> {
+        let range=self.block_range(offset, buf.len());
+        tokio::task::block_in_place(|| {
+            for (block_id, _range_block, range_local) in range.iter(){
+                self.put_block(*block_id, &buf[range_local.clone()], write_through)?;
+            }
+            Ok(())
+        })
+    }
+
+    async unsafe fn unsafe_read(&mut self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        self.unsafe_read_shared(offset, buf).await
+    }
+
+    async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        let range=self.block_range(offset, buf.len());
+        tokio::task::block_in_place(|| {
+            for (block_id, _range_block, range_local) in range.iter(){
+                self.get_block(*block_id, &mut buf[range_local.clone()])?;
+            }
+            Ok(())
+        })
+    }
+
+    async unsafe fn unsafe_discard(&mut self, offset: usize, size: usize) -> std::io::Result<()> {
+        // Only blocks with a file need deleting, however large the range.
+        let allocated: Vec<usize>=self.allocated_range(offset, size).cloned().collect();
+        tokio::task::block_in_place(|| {
+            for block_id in allocated{
+                self.delete_block(block_id)?;
+            }
+            Ok(())
+        })
+    }
+
+    async unsafe fn unsafe_write_zeroes(&mut self, offset: usize, size: usize, may_trim: bool, fast_only: bool, write_through: bool) -> std::io::Result<()> {
+        if may_trim{
+            // Missing blocks read as zeroes.
+            self.unsafe_discard(offset, size).await?;
+            if write_through{
+                tokio::task::block_in_place(|| self.sync_root())?;
+            }
+            Ok(())
+        }else if fast_only{
+            Err(ErrorKind::Unsupported)?
+        }else{
+            let range=self.block_range(offset, size);
+            let zeroes=vec![0; self.block_size()];
+            for (block_id, _range_block, _range_local) in range.iter(){
+                self.unsafe_write_block(*block_id, &zeroes, write_through).await?;
+            }
+            Ok(())
+        }
+    }
+
+    async unsafe fn unsafe_block_status(&mut self, offset: usize, size: usize) -> std::io::Result<Vec<Extent>> {
+        let (block_size, end)=(self.block_size, offset+size);
+        let mut extents=Vec::new();
+        let mut position=offset;
+        for block_id in self.allocated_range(offset, size){
+            let block_start=std::cmp::max(block_id*block_size, position);
+            let block_end=std::cmp::min((block_id+1)*block_size, end);
+            push_extent(&mut extents, Extent::hole(block_start-position));
+            push_extent(&mut extents, Extent::data(block_end-block_start));
+            position=block_end;
+        }
+        push_extent(&mut extents, Extent::hole(end-position));
+        Ok(extents)
+    }
+
+    async fn resize(&mut self, size: usize) -> std::io::Result<()> {
+        if size==0 || size % self.block_size!=0{
+            return Err(ErrorKind::InvalidInput)?;
+        }
+        // Drop the blocks past the new end, so that growing again brings back zeroes.
+        let truncated:Vec<usize>=self.allocated_blocks.range(size/self.block_size..).cloned().collect();
+        tokio::task::block_in_place(|| {
+            for block_id in truncated{
+                self.delete_block(block_id)?;
+            }
+            self.sync_root()
+        })?;
+        self.total_size=size;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities{trim: true, fua: true, rotational: false, resize: true, read_only: false, shared_read: true}
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        tokio::task::block_in_place(|| {
+            for block_id in self.unsynced_blocks.iter(){
+                File::open(self.block_path(*block_id))?.sync_data()?;
+            }
+            self.sync_root()
+        })?;
+        self.unsynced_blocks.clear();
+        Ok(())
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
+
+struct DirectoryFactory;
+#[async_trait]
+impl BackendFactory for DirectoryFactory{
+    async fn create(&self, spec: &ProviderSpec, size: Option<usize>) -> Result<Box<dyn CloudProvider>, Box<dyn std::error::Error>> {
+        let block_size=if spec.args.contains_key("block") {spec.size_arg("block")?} else {super::seafile::BLOCK_SIZE};
+        Ok(Box::new(DirectoryProvider::open(spec.location()?, spec.size(size)?, block_size)?))
+    }
+}
+/// Registers `directory(size=1G, block=1K):///path/to/volume`. Blocks default to the size Seafile uses.
+pub fn register(registry: &mut Registry){
+    registry.register_backend("directory", DirectoryFactory);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::support::run_in_task;
+
+    const BLOCK: usize=1024;
+
+    /// A directory in the temporary directory that no other test uses, emptied beforehand.
+    fn temp_dir(name: &str)->String{
+        let path=std::env::temp_dir().join(format!("clouddrive-directory-{}-{}", std::process::id(), name));
+        let _=std::fs::remove_dir_all(&path);
+        String::from(path.to_str().unwrap())
+    }
+    fn block_files(path: &str)->Vec<String>{
+        let mut names: Vec<String>=std::fs::read_dir(path).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn round_trips(){
+        run_in_task(async {
+            let path=temp_dir("round-trip");
+            let mut directory=DirectoryProvider::open(&path, 8*BLOCK, BLOCK).unwrap();
+            directory.write(2*BLOCK, &vec![7; 2*BLOCK], false).await.unwrap();
+            directory.flush().await.unwrap();
+            assert_eq!(block_files(&path), ["2.block", "3.block"]);
+            drop(directory);
+            // Blocks already in the directory are found again.
+            let mut directory=DirectoryProvider::open(&path, 8*BLOCK, BLOCK).unwrap();
+            let mut buf=vec![1; 8*BLOCK];
+            directory.read(0, &mut buf).await.unwrap();
+            assert!(buf[..2*BLOCK].iter().all(|byte| *byte==0));
+            assert!(buf[2*BLOCK..4*BLOCK].iter().all(|byte| *byte==7));
+            assert!(buf[4*BLOCK..].iter().all(|byte| *byte==0));
+            assert_eq!(directory.block_status(0, 8*BLOCK).await.unwrap(), [Extent::hole(2*BLOCK), Extent::data(2*BLOCK), Extent::hole(4*BLOCK)]);
+            std::fs::remove_dir_all(&path).unwrap();
+        });
+    }
+
+    #[test]
+    fn discards_allocated_blocks(){
+        run_in_task(async {
+            let path=temp_dir("discard");
+            let mut directory=DirectoryProvider::open(&path, 1024*BLOCK, BLOCK).unwrap();
+            for block_id in [0, 5, 6, 1023].iter(){
+                directory.write(block_id*BLOCK, &vec![7; BLOCK], false).await.unwrap();
+            }
+            directory.discard(BLOCK, 1022*BLOCK).await.unwrap();
+            assert_eq!(block_files(&path), ["0.block", "1023.block"]);
+            assert_eq!(directory.block_status(0, 1024*BLOCK).await.unwrap(), [Extent::data(BLOCK), Extent::hole(1022*BLOCK), Extent::data(BLOCK)]);
+            assert_eq!(directory.block_status(BLOCK, BLOCK).await.unwrap(), [Extent::hole(BLOCK)]);
+            directory.write_zeroes(0, 1024*BLOCK, true, true, true).await.unwrap();
+            assert!(block_files(&path).is_empty());
+            let mut buf=vec![1; BLOCK];
+            directory.read(1023*BLOCK, &mut buf).await.unwrap();
+            assert!(buf.iter().all(|byte| *byte==0));
+            std::fs::remove_dir_all(&path).unwrap();
+        });
+    }
+
+    #[test]
+    fn shrinking_drops_blocks(){
+        run_in_task(async {
+            let path=temp_dir("resize");
+            let mut directory=DirectoryProvider::open(&path, 4*BLOCK, BLOCK).unwrap();
+            directory.write(0, &vec![7; 4*BLOCK], false).await.unwrap();
+            directory.resize(2*BLOCK).await.unwrap();
+            directory.resize(4*BLOCK).await.unwrap();
+            assert_eq!(block_files(&path), ["0.block", "1.block"]);
+            assert_eq!(directory.resize(BLOCK+1).await.err().unwrap().kind(), ErrorKind::InvalidInput);
+            std::fs::remove_dir_all(&path).unwrap();
+        });
+    }
+}
@@ -0,0 +1,281 @@
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::cmp::min;
+use async_trait::async_trait;
+use super::{Capabilities, CloudProvider, Extent, push_extent, ZEROES_CHUNK_SIZE};
+use super::registry::{BackendFactory, ProviderSpec, Registry};
+
+/// Stores the data in a local file or block device.
+/// The blocking file operations run in place on the worker thread, which requires the threaded runtime.
+pub struct FileProvider{
+    file: File,
+    total_size: usize,
+    block_device: bool,
+    rotational: bool
+}
+
+impl FileProvider{
+    /// Opens a file or block device. With `size`, a regular file is created sparsely if missing or empty,
+    /// and an existing one must already have that size, so that a wrong size never truncates an image.
+    /// Without `size` the current size is used.
+    pub fn open(path: &str, size: Option<usize>)->std::io::Result<FileProvider>{
+        if size.map(|size| size==0 || size % crate::nbd::PREFERRED_BLOCK_SIZE!=0).unwrap_or(false){
+            return Err(ErrorKind::InvalidInput)?;
+        }
+        let file=OpenOptions::new().read(true).write(true).create(size.is_some()).open(path)?;
+        let metadata=file.metadata()?;
+        let block_device=metadata.file_type().is_block_device();
+        let total_size=if block_device{
+            // The length of a block device is only known by seeking to its end.
+            let end=unsafe {libc::lseek(file.as_raw_fd(), 0, libc::SEEK_END)};
+            if end<0{
+                return Err(std::io::Error::last_os_error());
+            }
+            if size.map(|size| size as libc::off_t!=end).unwrap_or(false){
+                return Err(ErrorKind::InvalidInput)?;
+            }
+            end as usize
+        }else{
+            match size{
+                Some(size) if metadata.len()==0=>{
+                    file.set_len(size as u64)?;
+                    size
+                }
+                Some(size) if metadata.len() as usize!=size=>return Err(ErrorKind::InvalidInput)?,
+                _=>metadata.len() as usize
+            }
+        };
+        if total_size==0 || total_size % crate::nbd::PREFERRED_BLOCK_SIZE!=0{
+            return Err(ErrorKind::InvalidInput)?;
+        }
+        let rotational=block_device && is_rotational(metadata.rdev());
+        Ok(FileProvider{file, total_size, block_device, rotational})
+    }
+    fn fallocate(&self, mode: libc::c_int, offset: usize, size: usize)->std::io::Result<()>{
+        let result=tokio::task::block_in_place(|| unsafe {
+            libc::fallocate(self.file.as_raw_fd(), mode, offset as libc::off_t, size as libc::off_t)
+        });
+        if result<0{
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    fn punch_hole(&self, offset: usize, size: usize)->std::io::Result<()>{
+        self.fallocate(libc::FALLOC_FL_PUNCH_HOLE|libc::FALLOC_FL_KEEP_SIZE, offset, size)
+    }
+    /// Offset of the next data or hole at or after `offset`, as found by SEEK_DATA or SEEK_HOLE.
+    fn seek(&self, offset: usize, whence: libc::c_int)->std::io::Result<usize>{
+        let result=unsafe {libc::lseek(self.file.as_raw_fd(), offset as libc::off_t, whence)};
+        if result<0{
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(result as usize)
+    }
+}
+/// Whether the kernel reports a block device as a spinning disk. Partitions inherit it from their disk.
+fn is_rotational(rdev: u64)->bool{
+    let (major, minor)=unsafe {(libc::major(rdev as libc::dev_t), libc::minor(rdev as libc::dev_t))};
+    let device=format!("/sys/dev/block/{}:{}", major, minor);
+    [format!("{}/queue/rotational", device), format!("{}/../queue/rotational", device)].iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .next()
+        .map(|value| value.trim()=="1")
+        .unwrap_or(false)
+}
+fn is_unsupported(err: &std::io::Error)->bool{
+    err.raw_os_error()==Some(libc::EOPNOTSUPP) || err.raw_os_error()==Some(libc::ENOSYS)
+}
+#[async_trait]
+impl CloudProvider for FileProvider {
+    fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    async unsafe fn unsafe_write(&mut self, offset: usize, buf: &[u8], write_through: bool) -> std::io::Result<()> {
+        let file=&self.file;
+        tokio::task::block_in_place(|| {
+            file.write_all_at(buf, offset as u64)?;
+            if write_through{
+                file.sync_data()?;
+            }
+            Ok(())
+        })
+    }
+
+    async unsafe fn unsafe_read(&mut self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        self.unsafe_read_shared(offset, buf).await
+    }
+
+    async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        let file=&self.file;
+        tokio::task::block_in_place(|| file.read_exact_at(buf, offset as u64))
+    }
+
+    async unsafe fn unsafe_discard(&mut self, offset: usize, size: usize) -> std::io::Result<()> {
+        match self.punch_hole(offset, size){
+            // Discarding is only a hint.
+            Err(err) if is_unsupported(&err)=>Ok(()),
+            result=>result
+        }
+    }
+
+    async unsafe fn unsafe_write_zeroes(&mut self, offset: usize, size: usize, may_trim: bool, fast_only: bool, write_through: bool) -> std::io::Result<()> {
+        let mut result=if may_trim {self.punch_hole(offset, size)} else {Err(ErrorKind::Unsupported.into())};
+        if result.is_err(){
+            result=self.fallocate(libc::FALLOC_FL_ZERO_RANGE|libc::FALLOC_FL_KEEP_SIZE, offset, size);
+        }
+        match result{
+            Ok(())=>{
+                if write_through{
+                    self.flush().await?;
+                }
+                Ok(())
+            }
+            Err(err) if !is_unsupported(&err)=>Err(err),
+            Err(_) if fast_only=>Err(ErrorKind::Unsupported)?,
+            Err(_)=>{
+                let zeroes=vec![0; min(ZEROES_CHUNK_SIZE, size)];
+                let mut position=offset;
+                while position<offset+size{
+                    let length=min(zeroes.len(), offset+size-position);
+                    self.unsafe_write(position, &zeroes[..length], write_through).await?;
+                    position+=length;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async unsafe fn unsafe_block_status(&mut self, offset: usize, size: usize) -> std::io::Result<Vec<Extent>> {
+        let end=offset+size;
+        let mut extents=Vec::new();
+        let mut position=offset;
+        while position<end{
+            let data=match self.seek(position, libc::SEEK_DATA){
+                Ok(data)=>min(data, end),
+                // Nothing but a hole up to the end of the file.
+                Err(err) if err.raw_os_error()==Some(libc::ENXIO)=>end,
+                // SEEK_DATA is not supported here, e.g. on block devices.
+                Err(_)=>{
+                    push_extent(&mut extents, Extent::data(end-position));
+                    break;
+                }
+            };
+            push_extent(&mut extents, Extent::hole(data-position));
+            if data==end{
+                break;
+            }
+            let hole=min(self.seek(data, libc::SEEK_HOLE)?, end);
+            push_extent(&mut extents, Extent::data(hole-data));
+            position=hole;
+        }
+        Ok(extents)
+    }
+
+    async fn resize(&mut self, size: usize) -> std::io::Result<()> {
+        if self.block_device{
+            return Err(ErrorKind::Unsupported)?;
+        }
+        if size==0 || size % self.block_size()!=0{
+            return Err(ErrorKind::InvalidInput)?;
+        }
+        let file=&self.file;
+        tokio::task::block_in_place(|| file.set_len(size as u64))?;
+        self.total_size=size;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities{trim: true, fua: true, rotational: self.rotational, resize: !self.block_device, read_only: false, shared_read: true}
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        let file=&self.file;
+        tokio::task::block_in_place(|| file.sync_data())
+    }
+
+    fn block_size(&self) -> usize {
+        crate::nbd::PREFERRED_BLOCK_SIZE
+    }
+}
+
+struct FileFactory;
+#[async_trait]
+impl BackendFactory for FileFactory{
+    async fn create(&self, spec: &ProviderSpec, size: Option<usize>) -> Result<Box<dyn CloudProvider>, Box<dyn std::error::Error>> {
+        let size=if spec.args.contains_key("size") || size.is_some() {Some(spec.size(size)?)} else {None};
+        Ok(Box::new(FileProvider::open(spec.location()?, size)?))
+    }
+}
+/// Registers `file(size=1G):///path/to/image`. A missing file is created with the given size,
+/// an existing one must match it, and without a size the file must already exist.
+pub fn register(registry: &mut Registry){
+    registry.register_backend("file", FileFactory);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::support::run_in_task;
+
+    const BLOCK: usize=crate::nbd::PREFERRED_BLOCK_SIZE;
+
+    /// A path in the temporary directory that no other test uses, removed beforehand.
+    fn temp_path(name: &str)->String{
+        let path=std::env::temp_dir().join(format!("clouddrive-file-{}-{}", std::process::id(), name));
+        let _=std::fs::remove_file(&path);
+        String::from(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn round_trips(){
+        run_in_task(async {
+            let path=temp_path("round-trip");
+            let mut file=FileProvider::open(&path, Some(4*BLOCK)).unwrap();
+            assert_eq!(file.total_size(), 4*BLOCK);
+            file.write(BLOCK, &vec![7; 2*BLOCK], true).await.unwrap();
+            drop(file);
+            // Without a size the file keeps its own.
+            let mut file=FileProvider::open(&path, None).unwrap();
+            assert_eq!(file.total_size(), 4*BLOCK);
+            let mut buf=vec![1; 4*BLOCK];
+            file.read(0, &mut buf).await.unwrap();
+            assert!(buf[..BLOCK].iter().all(|byte| *byte==0));
+            assert!(buf[BLOCK..3*BLOCK].iter().all(|byte| *byte==7));
+            assert!(buf[3*BLOCK..].iter().all(|byte| *byte==0));
+            file.write_zeroes(BLOCK, BLOCK, false, false, false).await.unwrap();
+            file.read(BLOCK, &mut buf[..BLOCK]).await.unwrap();
+            assert!(buf[..BLOCK].iter().all(|byte| *byte==0));
+            let extents=file.block_status(0, 4*BLOCK).await.unwrap();
+            assert_eq!(extents.iter().map(|extent| extent.length).sum::<usize>(), 4*BLOCK);
+            std::fs::remove_file(&path).unwrap();
+        });
+    }
+
+    #[test]
+    fn never_resizes_existing_files(){
+        let path=temp_path("resize");
+        FileProvider::open(&path, Some(4*BLOCK)).unwrap();
+        assert_eq!(FileProvider::open(&path, Some(2*BLOCK)).err().unwrap().kind(), ErrorKind::InvalidInput);
+        assert_eq!(FileProvider::open(&path, Some(8*BLOCK)).err().unwrap().kind(), ErrorKind::InvalidInput);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4*BLOCK as u64);
+        assert!(FileProvider::open(&path, Some(4*BLOCK)).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_sizes(){
+        let path=temp_path("bad-size");
+        assert_eq!(FileProvider::open(&path, None).err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(FileProvider::open(&path, Some(0)).err().unwrap().kind(), ErrorKind::InvalidInput);
+        assert_eq!(FileProvider::open(&path, Some(BLOCK+1)).err().unwrap().kind(), ErrorKind::InvalidInput);
+        assert!(std::fs::metadata(&path).is_err());
+        // Empty files are only usable once given a size.
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(FileProvider::open(&path, None).err().unwrap().kind(), ErrorKind::InvalidInput);
+        assert_eq!(FileProvider::open(&path, Some(BLOCK)).unwrap().total_size(), BLOCK);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+use reqwest::*;
+use async_trait::async_trait;
+use crate::support::{Capabilities, CloudProvider};
+use crate::support::registry::{BackendFactory, ProviderSpec, Registry, RegistryError};
+use crate::support::retry::{retry, transient_status, Transient};
+use std::io::ErrorKind;
+use std::fmt;
+
+/// Size of the blocks reads are aligned to, unless configured otherwise.
+pub const DEFAULT_BLOCK_SIZE:usize=64*1024;
+
+/// Serves a raw disk image published on a plain HTTP server, reading only the parts asked for with
+/// `Range` requests. Writes are refused; stack a `cow` layer on top to run a VM off the image,
+/// and an `lru` layer underneath it to avoid fetching the same blocks again.
+/// The size is rounded up to a whole block, and the padding reads as zeroes.
+pub struct HttpRangeProvider {
+    http: Client,
+    url: String,
+    /// Size of the image on the server.
+    image_size: usize,
+    block_size: usize
+}
+#[derive(Debug)]
+pub enum HttpRangeError{
+    NoImage(u16),
+    MissingLength,
+    /// The server ignored a `Range` header, or advertised that it does not support them.
+    NoRangeSupport,
+    /// The server sent another part of the image than asked for.
+    BadRange(String),
+    Io(Box<dyn std::error::Error+Send+Sync>),
+    BadResponse(u16)
+}
+impl fmt::Display for HttpRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self{
+            HttpRangeError::NoImage(status)=>write!(f, "HTTP image not available, status {}", status),
+            HttpRangeError::MissingLength=>write!(f, "HTTP server did not report the image size"),
+            HttpRangeError::NoRangeSupport=>write!(f, "HTTP server does not support range requests"),
+            HttpRangeError::BadRange(content_range)=>write!(f, "HTTP server sent another range than requested: {:?}", content_range),
+            HttpRangeError::Io(err)=>write!(f, "HTTP request failed: {}", err),
+            HttpRangeError::BadResponse(status)=>write!(f, "HTTP request failed with status {}", status)
+        }
+    }
+}
+impl std::error::Error for HttpRangeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // Generic error, underlying cause isn't tracked.
+        None
+    }
+}
+impl From<HttpRangeError> for std::io::Error{
+    fn from(e: HttpRangeError) -> Self {
+        std::io::Error::new(ErrorKind::Other, e)
+    }
+}
+impl From<reqwest::Error> for HttpRangeError{
+    fn from(e: reqwest::Error) -> Self {
+        HttpRangeError::Io(Box::new(e))
+    }
+}
+impl Transient for HttpRangeError{
+    fn is_transient(&self)->bool{
+        match self{
+            HttpRangeError::Io(_)=>true,
+            HttpRangeError::BadResponse(status)=>transient_status(*status),
+            _=>false
+        }
+    }
+}
+pub type Result<T>=std::result::Result<T, HttpRangeError>;
+
+/// The first byte of a `Content-Range: bytes {first}-{last}/{size}` header.
+fn content_range_start(value: &str)->Option<usize>{
+    let range=value.trim().strip_prefix("bytes ")?;
+    range[..range.find('-')?].trim().parse::<usize>().ok()
+}
+impl HttpRangeProvider{
+    /// Finds the size of the image with a HEAD request.
+    pub async fn connect(url: &str, block_size: usize)->Result<Self>{
+        let mut client_builder=ClientBuilder::new().user_agent("CloudDrive HTTP Provider").tcp_nodelay();
+        if let Ok(url)=std::env::var("https_proxy"){
+            client_builder=client_builder.proxy(Proxy::https(&url).unwrap());
+        }
+        if let Ok(url)=std::env::var("http_proxy"){
+            client_builder=client_builder.proxy(Proxy::http(&url).unwrap());
+        }
+        let http=client_builder.build().unwrap();
+        let response=http.head(url).send().await?;
+        if response.status()!=StatusCode::OK{
+            return Err(HttpRangeError::NoImage(response.status().as_u16()));
+        }
+        if response.headers().get(header::ACCEPT_RANGES).map(|value| value.as_bytes()==b"none").unwrap_or(false){
+            return Err(HttpRangeError::NoRangeSupport);
+        }
+        // reqwest reports no content length for HEAD responses, so the header is read directly.
+        let image_size=response.headers().get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok())
+            .filter(|size| *size>0)
+            .ok_or(HttpRangeError::MissingLength)?;
+        Ok(HttpRangeProvider{http, url: String::from(url), image_size, block_size})
+    }
+    /// Reads `length` bytes at `offset`, which must lie within the image.
+    async fn get_range(&self, offset: usize, length: usize)->Result<bytes::Bytes>{
+        let range=format!("bytes={}-{}", offset, offset+length-1);
+        let response=self.http.get(&self.url).header(header::RANGE, range).send().await?;
+        match response.status(){
+            StatusCode::PARTIAL_CONTENT=>{
+                let content_range=response.headers().get(header::CONTENT_RANGE).and_then(|value| value.to_str().ok()).unwrap_or("");
+                if content_range_start(content_range)!=Some(offset){
+                    return Err(HttpRangeError::BadRange(String::from(content_range)));
+                }
+                let bytes=response.bytes().await?;
+                if bytes.len()!=length{
+                    return Err(HttpRangeError::BadResponse(StatusCode::PARTIAL_CONTENT.as_u16()));
+                }
+                Ok(bytes)
+            }
+            // The whole image is on its way, which is never what we want.
+            StatusCode::OK=>Err(HttpRangeError::NoRangeSupport),
+            status=>Err(HttpRangeError::BadResponse(status.as_u16()))
+        }
+    }
+}
+#[async_trait]
+impl CloudProvider for HttpRangeProvider {
+    fn total_size(&self) -> usize {
+        (self.image_size+self.block_size-1)/self.block_size*self.block_size
+    }
+
+    async unsafe fn unsafe_write(&mut self, _offset: usize, _buf: &[u8], _write_through: bool) -> std::io::Result<()> {
+        Err(ErrorKind::PermissionDenied)?
+    }
+
+    async unsafe fn unsafe_read(&mut self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        self.unsafe_read_shared(offset, buf).await
+    }
+
+    async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        // The padding past the end of the image reads as zeroes.
+        let length=if offset<self.image_size {std::cmp::min(buf.len(), self.image_size-offset)} else {0};
+        for byte in buf[length..].iter_mut(){
+            *byte=0;
+        }
+        if length==0{
+            return Ok(());
+        }
+        let bytes=retry("HTTP get_range", || self.get_range(offset, length)).await?;
+        buf[..length].copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    async unsafe fn unsafe_write_zeroes(&mut self, _offset: usize, _size: usize, _may_trim: bool, _fast_only: bool, _write_through: bool) -> std::io::Result<()> {
+        Err(ErrorKind::PermissionDenied)?
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities{read_only: true, shared_read: true, ..Capabilities::default()}
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
+struct HttpRangeFactory;
+#[async_trait]
+impl BackendFactory for HttpRangeFactory{
+    async fn create(&self, spec: &ProviderSpec, size: Option<usize>) -> std::result::Result<Box<dyn CloudProvider>, Box<dyn std::error::Error>> {
+        let url=format!("{}://{}", spec.name, spec.location()?);
+        let block_size=if spec.args.contains_key("block") {spec.size_arg("block")?} else {DEFAULT_BLOCK_SIZE};
+        if block_size==0{
+            return Err(RegistryError::BadArgument(format!("{}.block=0", spec.name)))?;
+        }
+        let provider=HttpRangeProvider::connect(&url, block_size).await?;
+        // The size comes from the server, so a configured one can only confirm it.
+        if spec.args.contains_key("size") || size.is_some(){
+            let size=spec.size(size)?;
+            if size!=provider.total_size(){
+                return Err(RegistryError::BadArgument(format!("{}.size={}", spec.name, size)))?;
+            }
+        }
+        Ok(Box::new(provider))
+    }
+}
+/// Registers `http(block=64K)://server/image.raw` and the same with `https`. The size comes from the server,
+/// rounded up to whole blocks, and a size given anyway must match it.
+pub fn register(registry: &mut Registry){
+    registry.register_backend("http", HttpRangeFactory);
+    registry.register_backend("https", HttpRangeFactory);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn reads_content_range_start(){
+        assert_eq!(content_range_start("bytes 0-4095/1048576"), Some(0));
+        assert_eq!(content_range_start("bytes 65536-131071/*"), Some(65536));
+        assert_eq!(content_range_start(" bytes 42-42/43 "), Some(42));
+        assert_eq!(content_range_start("bytes */1048576"), None);
+        assert_eq!(content_range_start("items 0-9/10"), None);
+        assert_eq!(content_range_start(""), None);
+    }
+}
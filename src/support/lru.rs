@@ -1,8 +1,9 @@
-use super::{CloudProvider, CloudProviderExt};
+use super::{Capabilities, CloudProvider, CloudProviderExt, Extent, push_extent};
+use super::registry::{LayerFactory, ProviderSpec, Registry, RegistryError};
 use std::ops::{Range};
 use std::pin::Pin;
 use lru::LruCache;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::cmp::{max, min};
 use async_trait::async_trait;
 use std::slice::SliceIndex;
@@ -13,7 +14,8 @@ pub struct LRUItem{
 /// Wrapper for any cloud provider, with local LRU cache.
 pub struct LRUProvider<T: CloudProvider>{
     provider: T,
-    cache: LruCache<usize, LRUItem>,
+    /// Locked by shared reads, and only ever for a moment: never across a call to the provider.
+    cache: Mutex<LruCache<usize, LRUItem>>,
     capacity: usize
 }
 
@@ -23,9 +25,46 @@ impl<T: CloudProvider> LRUProvider<T>{
         LRUProvider{
             provider,
             capacity,
-            cache: LruCache::unbounded()
+            cache: Mutex::new(LruCache::unbounded())
         }
     }
+    /// Puts a block into the cache, writing back the least recently used block if the cache is full.
+    async unsafe fn insert(&mut self, block_id: usize, item: LRUItem)->std::io::Result<()>{
+        let cache=self.cache.get_mut().unwrap();
+        if cache.len()==self.capacity {
+            let (evicted_id, evicted_item)=cache.pop_lru().unwrap(); // assert capacity>0.
+            if evicted_item.dirty {
+                self.provider.unsafe_write_block(evicted_id, &evicted_item.data, false).await?;
+            }
+        }
+        cache.put(block_id, item);
+        Ok(())
+    }
+    /// Puts a block read through a shared reference into the cache. Writing back an evicted dirty block
+    /// needs exclusive access, so the block is left out instead if that would be necessary.
+    fn insert_shared(&self, block_id: usize, data: Vec<u8>){
+        let mut cache=self.cache.lock().unwrap();
+        // Another read may have loaded the block in the meantime.
+        if cache.contains(&block_id){
+            return;
+        }
+        if cache.len()==self.capacity{
+            match cache.peek_lru(){
+                Some((_, evicted_item)) if !evicted_item.dirty=>{cache.pop_lru();}
+                _=>return
+            }
+        }
+        cache.put(block_id, LRUItem{data, dirty: false});
+    }
+    /// Cached blocks overlapping a range, in order. Found through the cache rather than the range,
+    /// which may span the whole export.
+    fn cached_blocks(&self, offset: usize, size: usize)->Vec<usize>{
+        let block_size=self.block_size();
+        let (first_block, last_block)=(offset/block_size, (offset+size-1)/block_size);
+        let mut blocks: Vec<usize>=self.cache.lock().unwrap().iter().map(|(block_id, _)| *block_id).filter(|block_id| (first_block..=last_block).contains(block_id)).collect();
+        blocks.sort();
+        blocks
+    }
 
 }
 #[async_trait]
@@ -38,7 +77,7 @@ impl<T: CloudProvider> CloudProvider for LRUProvider<T>{
         let range=self.block_range(offset, buf.len());
         let block_size=self.block_size();
         for (block_id, _range_block, range_local) in range.iter(){
-            if let Some(block)=self.cache.get_mut(block_id){
+            if let Some(block)=self.cache.get_mut().unwrap().get_mut(block_id){
                 // Copy to cache and mark as dirty.
                 std::ptr::copy_nonoverlapping(buf.as_ptr().add(range_local.start) as *const u8, block.data.as_mut_ptr() as *mut u8, block_size);
                 if write_through{
@@ -52,19 +91,13 @@ impl<T: CloudProvider> CloudProvider for LRUProvider<T>{
                 let mut buffer=self.provider.create_block_buffer();
                 self.provider.unsafe_read_block(*block_id, &mut buffer).await?;
                 std::ptr::copy_nonoverlapping(buf.as_ptr().add(range_local.start), buffer.as_mut_ptr() as *mut u8, block_size);
-                // insert into cache.
-                if self.cache.len()==self.capacity {
-                    let (evicted_id, evicted_item)=self.cache.pop_lru().unwrap(); // assert capacity>0.
-                    if evicted_item.dirty {
-                        self.provider.unsafe_write_block(evicted_id, &evicted_item.data, false).await?;
-                    }
-                }
                 let mut lruitem=LRUItem {data: buffer, dirty: true};
                 if write_through{
                     self.provider.unsafe_write_block(*block_id, &lruitem.data, true).await?;
                     lruitem.dirty=false;
                 }
-                self.cache.put(*block_id, lruitem);
+                // insert into cache.
+                self.insert(*block_id, lruitem).await?;
             }
 
         }
@@ -75,7 +108,7 @@ impl<T: CloudProvider> CloudProvider for LRUProvider<T>{
         let range=self.block_range(offset, buf.len());
 
         for (block_id, _range_block, range_local) in range.iter(){
-            if let Some(block)=self.cache.get(block_id){
+            if let Some(block)=self.cache.get_mut().unwrap().get(block_id){
                 // Copy from cache.
                 std::ptr::copy_nonoverlapping( block.data.as_ptr() as *const u8, buf.as_mut_ptr().add(range_local.start) as *mut u8,self.block_size());
             }else{
@@ -84,24 +117,107 @@ impl<T: CloudProvider> CloudProvider for LRUProvider<T>{
                 self.provider.unsafe_read_block(*block_id, &mut buffer).await?;
                 std::ptr::copy_nonoverlapping(buffer.as_ptr() as *const u8, buf.as_mut_ptr().add(range_local.start) as *mut u8, self.block_size());
                 // insert into cache.
-                if self.cache.len()==self.capacity {
-                    let (evicted_id, evicted_item)=self.cache.pop_lru().unwrap(); // assert capacity>0.
-                    if evicted_item.dirty {
-                        self.provider.unsafe_write_block(evicted_id, &evicted_item.data, false).await?;
-                    }
-                }
                 let lruitem=LRUItem {data: buffer, dirty: false};
-                self.cache.put(*block_id, lruitem);
+                self.insert(*block_id, lruitem).await?;
+            }
+
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        let block_size=self.block_size();
+        for (block_id, _range_block, range_local) in self.block_range(offset, buf.len()).iter(){
+            let hit=match self.cache.lock().unwrap().get(block_id){
+                Some(block)=>{
+                    buf[Range::clone(range_local)].copy_from_slice(&block.data[..block_size]);
+                    true
+                }
+                None=>false
+            };
+            if !hit{
+                let mut buffer=self.provider.create_block_buffer();
+                self.provider.unsafe_read_block_shared(*block_id, &mut buffer).await?;
+                buf[Range::clone(range_local)].copy_from_slice(&buffer[..block_size]);
+                self.insert_shared(*block_id, buffer);
             }
+        }
+        Ok(())
+    }
 
+    async unsafe fn unsafe_prefetch(&mut self, offset: usize, size: usize) -> std::io::Result<()> {
+        // Blocks past the capacity would only evict the ones loaded before them.
+        let range=self.block_range(offset, size);
+        for (block_id, _range_block, _range_local) in range.iter().take(self.capacity){
+            if !self.cache.get_mut().unwrap().contains(block_id){
+                let mut buffer=self.provider.create_block_buffer();
+                self.provider.unsafe_read_block(*block_id, &mut buffer).await?;
+                self.insert(*block_id, LRUItem {data: buffer, dirty: false}).await?;
+            }
         }
         Ok(())
     }
 
+    async unsafe fn unsafe_discard(&mut self, offset: usize, size: usize) -> std::io::Result<()> {
+        // Cached copies, dirty or not, are stale once the range is discarded.
+        for block_id in self.cached_blocks(offset, size){
+            self.cache.get_mut().unwrap().pop(&block_id);
+        }
+        self.provider.unsafe_discard(offset, size).await
+    }
+
+    async unsafe fn unsafe_write_zeroes(&mut self, offset: usize, size: usize, may_trim: bool, fast_only: bool, write_through: bool) -> std::io::Result<()> {
+        self.provider.unsafe_write_zeroes(offset, size, may_trim, fast_only, write_through).await?;
+        // Only drop cached copies once zeroing succeeded, since they may hold dirty data.
+        for block_id in self.cached_blocks(offset, size){
+            self.cache.get_mut().unwrap().pop(&block_id);
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_block_status(&mut self, offset: usize, size: usize) -> std::io::Result<Vec<Extent>> {
+        // Cached blocks may not have reached the underlying provider yet, so they always count as data.
+        let underlying=self.provider.unsafe_block_status(offset, size).await?;
+        let block_size=self.block_size();
+        let mut extents=Vec::new();
+        let mut position=offset;
+        for extent in underlying{
+            let end=position+extent.length;
+            if !extent.hole && !extent.zero{
+                push_extent(&mut extents, extent);
+            }else{
+                for block_id in self.cached_blocks(position, extent.length){
+                    let block_start=max(block_id*block_size, position);
+                    let block_end=min((block_id+1)*block_size, end);
+                    push_extent(&mut extents, Extent{length: block_start-position, ..extent});
+                    push_extent(&mut extents, Extent::data(block_end-block_start));
+                    position=block_end;
+                }
+                push_extent(&mut extents, Extent{length: end-position, ..extent});
+            }
+            position=end;
+        }
+        Ok(extents)
+    }
+
+    async fn resize(&mut self, size: usize) -> std::io::Result<()> {
+        self.provider.resize(size).await?;
+        // Cached blocks past the new end must never be written back.
+        let block_size=self.block_size();
+        let truncated:Vec<usize>=self.cache.get_mut().unwrap().iter().map(|(block_id, _)| *block_id).filter(|block_id| (block_id+1)*block_size>size).collect();
+        for block_id in truncated.iter(){
+            self.cache.get_mut().unwrap().pop(block_id);
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.provider.capabilities()
+    }
 
     async fn flush(&mut self) -> std::io::Result<()> {
         unsafe {
-            for (block_id, lruitem) in self.cache.iter_mut() {
+            for (block_id, lruitem) in self.cache.get_mut().unwrap().iter_mut() {
                 if lruitem.dirty {
                     self.provider.unsafe_write_block(*block_id, &lruitem.data, true).await?;
                     lruitem.dirty = false;
@@ -114,4 +230,64 @@ impl<T: CloudProvider> CloudProvider for LRUProvider<T>{
     fn block_size(&self) -> usize {
         self.provider.block_size()
     }
-}
\ No newline at end of file
+}
+struct LRUFactory;
+impl LayerFactory for LRUFactory{
+    fn wrap(&self, spec: &ProviderSpec, provider: Box<dyn CloudProvider>) -> Result<Box<dyn CloudProvider>, Box<dyn std::error::Error>> {
+        let capacity=spec.required_arg::<usize>("cap")?;
+        if capacity==0{
+            return Err(RegistryError::BadArgument(String::from("lru.cap=0")))?;
+        }
+        Ok(Box::new(LRUProvider::new(provider, capacity)))
+    }
+}
+/// Registers `lru(cap=1024)`, with the capacity counted in blocks.
+pub fn register(registry: &mut Registry){
+    registry.register_layer("lru", LRUFactory);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::support::MemoryProvider;
+
+    const BLOCK: usize=crate::nbd::PREFERRED_BLOCK_SIZE;
+
+    fn cached(lru: &mut LRUProvider<MemoryProvider>)->Vec<(usize, bool)>{
+        let mut blocks: Vec<(usize, bool)>=lru.cache.get_mut().unwrap().iter().map(|(block_id, item)| (*block_id, item.dirty)).collect();
+        blocks.sort();
+        blocks
+    }
+
+    #[tokio::test]
+    async fn shared_reads_see_dirty_blocks(){
+        let mut lru=LRUProvider::new(MemoryProvider::new(4*BLOCK), 4);
+        lru.write(0, &vec![0; 4*BLOCK], false).await.unwrap();
+        lru.flush().await.unwrap();
+        lru.write(BLOCK, &vec![7; BLOCK], false).await.unwrap();
+        let mut buf=vec![1; 2*BLOCK];
+        lru.read_shared(0, &mut buf).await.unwrap();
+        assert!(buf[..BLOCK].iter().all(|byte| *byte==0));
+        assert!(buf[BLOCK..].iter().all(|byte| *byte==7));
+    }
+
+    #[tokio::test]
+    async fn shared_reads_never_evict_dirty_blocks(){
+        let mut lru=LRUProvider::new(MemoryProvider::new(4*BLOCK), 2);
+        lru.provider.write(0, &vec![3; 4*BLOCK], false).await.unwrap();
+        lru.write(0, &vec![7; BLOCK], false).await.unwrap();
+        let mut buf=vec![0; BLOCK];
+        lru.read_shared(BLOCK, &mut buf).await.unwrap();
+        assert_eq!(cached(&mut lru), [(0, true), (1, false)]);
+        // Evicting block 0 would need a write back, so block 2 is read without being cached.
+        lru.read_shared(2*BLOCK, &mut buf).await.unwrap();
+        assert!(buf.iter().all(|byte| *byte==3));
+        assert_eq!(cached(&mut lru), [(0, true), (1, false)]);
+        // Once block 0 is used again, block 1 is the least recently used and makes way as usual.
+        lru.read_shared(0, &mut buf).await.unwrap();
+        lru.read_shared(3*BLOCK, &mut buf).await.unwrap();
+        assert_eq!(cached(&mut lru), [(0, true), (3, false)]);
+        lru.read_shared(0, &mut buf).await.unwrap();
+        assert!(buf.iter().all(|byte| *byte==7));
+    }
+}
@@ -38,11 +38,37 @@ impl super::CloudProvider for MemoryProvider {
     }
 
     async unsafe fn unsafe_read(&mut self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
-        let content=&mut self.content;
-        std::ptr::copy_nonoverlapping(content.as_ptr().add(offset), buf.as_mut_ptr() as *mut u8, buf.len());
+        self.unsafe_read_shared(offset, buf).await
+    }
+
+    async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        std::ptr::copy_nonoverlapping(self.content.as_ptr().add(offset), buf.as_mut_ptr() as *mut u8, buf.len());
+        Ok(())
+    }
+
+    async unsafe fn unsafe_discard(&mut self, offset: usize, size: usize) -> std::io::Result<()> {
+        std::ptr::write_bytes(self.content.as_mut_ptr().add(offset), 0, size);
+        Ok(())
+    }
+
+    async unsafe fn unsafe_write_zeroes(&mut self, offset: usize, size: usize, _may_trim: bool, _fast_only: bool, _write_through: bool) -> std::io::Result<()> {
+        std::ptr::write_bytes(self.content.as_mut_ptr().add(offset), 0, size);
+        Ok(())
+    }
+
+    async fn resize(&mut self, size: usize) -> std::io::Result<()> {
+        if size % self.block_size() !=0{
+            return Err(std::io::ErrorKind::InvalidInput)?;
+        }
+        self.content.resize(size, 0);
+        self.size=size;
         Ok(())
     }
 
+    fn capabilities(&self) -> super::Capabilities {
+        super::Capabilities{trim: true, fua: true, rotational: false, resize: true, read_only: false, shared_read: true}
+    }
+
     async fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
@@ -50,4 +76,15 @@ impl super::CloudProvider for MemoryProvider {
     fn block_size(&self) -> usize {
         crate::nbd::PREFERRED_BLOCK_SIZE
     }
-}
\ No newline at end of file
+}
+struct MemoryFactory;
+#[async_trait]
+impl super::registry::BackendFactory for MemoryFactory{
+    async fn create(&self, spec: &super::registry::ProviderSpec, size: Option<usize>) -> Result<Box<dyn super::CloudProvider>, Box<dyn std::error::Error>> {
+        Ok(Box::new(MemoryProvider::new(spec.size(size)?)))
+    }
+}
+/// Registers `memory(size=1G)`.
+pub fn register(registry: &mut super::registry::Registry){
+    registry.register_backend("memory", MemoryFactory);
+}
@@ -1,28 +1,99 @@
 use std::io::ErrorKind;
 use std::cmp::{max, min};
 use std::ops::Range;
-use tokio::sync::Mutex;
+use tokio::sync::RwLock;
 use async_trait::async_trait;
 mod lru;
 mod memory;
+mod file;
+mod directory;
 mod byte;
+mod cow;
 pub mod seafile;
-use std::ops::DerefMut;
+pub mod s3;
+pub mod webdav;
+pub mod http;
+pub mod registry;
+mod retry;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub use self::byte::ByteGranularityProvider;
 pub use self::lru::LRUProvider;
+pub use self::cow::CowProvider;
 pub use self::memory::MemoryProvider;
+pub use self::file::FileProvider;
+pub use self::directory::DirectoryProvider;
 pub use self::seafile::SeafileProvider;
+pub use self::s3::S3Provider;
+pub use self::webdav::WebDavProvider;
+pub use self::http::HttpRangeProvider;
+pub use self::registry::Registry;
+/// Largest zero-filled buffer the default `unsafe_write_zeroes` writes at once.
+const ZEROES_CHUNK_SIZE:usize=1024*1024;
 pub fn bound_and_align_check(block_size: usize, total_size: usize, offset: usize, size: usize)->bool{
     if offset%block_size!=0 || size%block_size!=0 {
         return false;
-    }else if offset+size > total_size{
+    }else if offset.checked_add(size).map(|end| end>total_size).unwrap_or(true){
         return false;
     } else if offset>=total_size{
         return false;
     }
     true
 }
+/// Allocation state of a contiguous range of a provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent{
+    pub length: usize,
+    /// The range is not backed by storage.
+    pub hole: bool,
+    /// The range reads as zeroes.
+    pub zero: bool
+}
+impl Extent{
+    pub fn data(length: usize)->Self{
+        Extent{length, hole: false, zero: false}
+    }
+    pub fn hole(length: usize)->Self{
+        Extent{length, hole: true, zero: true}
+    }
+}
+/// Optional features of a provider, from which the flags advertised to clients are computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities{
+    /// `discard` actually frees storage.
+    pub trim: bool,
+    /// Writes with `write_through` are durable once they complete.
+    pub fua: bool,
+    /// Seeks are expensive, so clients should prefer sequential access.
+    pub rotational: bool,
+    /// `resize` is supported.
+    pub resize: bool,
+    /// Writes are refused, so exports of the provider are always read-only.
+    pub read_only: bool,
+    /// `unsafe_read_shared` is supported, so reads may run concurrently with each other.
+    pub shared_read: bool
+}
+/// Copies a downloaded block to the start of `buf` and zeroes the rest, since missing and short blocks read as zeroes.
+pub fn copy_padded(buf: &mut [u8], data: &[u8]){
+    let length=min(data.len(), buf.len());
+    buf[..length].copy_from_slice(&data[..length]);
+    for byte in buf[length..].iter_mut(){
+        *byte=0;
+    }
+}
+/// Appends an extent to a list, merging it into the last one if both have the same state.
+pub fn push_extent(extents: &mut Vec<Extent>, extent: Extent){
+    if extent.length==0 {
+        return;
+    }
+    if let Some(last)=extents.last_mut(){
+        if last.hole==extent.hole && last.zero==extent.zero{
+            last.length+=extent.length;
+            return;
+        }
+    }
+    extents.push(extent);
+}
 #[async_trait]
 pub trait CloudProvider: Send+Sync {
     fn total_size(&self)->usize;
@@ -43,16 +114,139 @@ pub trait CloudProvider: Send+Sync {
             unsafe {self.unsafe_read(offset, buf).await}
         }
     }
+    /// Reads through a shared reference, so that reads can overlap with each other, though never with
+    /// anything else. Only called on providers whose capabilities include `shared_read`.
+    async unsafe fn unsafe_read_shared(&self, _offset: usize, _buf: &mut [u8])->std::io::Result<()>{
+        Err(ErrorKind::Unsupported)?
+    }
+    async fn read_shared(&self, offset: usize, buf: &mut [u8])->std::io::Result<()>{
+        if !bound_and_align_check(self.block_size(), self.total_size(), offset, buf.len()) {
+            return Err(ErrorKind::InvalidInput)?;
+        }else{
+            unsafe {self.unsafe_read_shared(offset, buf).await}
+        }
+    }
+    /// Asks the provider to get a range ready for upcoming reads, e.g. by loading it into a cache.
+    /// Providers without anything to warm up ignore the hint.
+    async unsafe fn unsafe_prefetch(&mut self, _offset: usize, _size: usize)->std::io::Result<()>{
+        Ok(())
+    }
+    async fn prefetch(&mut self, offset: usize, size: usize)->std::io::Result<()>{
+        if !bound_and_align_check(self.block_size(), self.total_size(), offset, size) {
+            return Err(ErrorKind::InvalidInput)?;
+        }else{
+            unsafe {self.unsafe_prefetch(offset, size).await}
+        }
+    }
+    /// Tells the provider that a range is no longer needed, so that it may free the storage behind it.
+    /// The content of a discarded range is unspecified, and providers are free to ignore the hint.
+    async unsafe fn unsafe_discard(&mut self, _offset: usize, _size: usize)->std::io::Result<()>{
+        Ok(())
+    }
+    async fn discard(&mut self, offset: usize, size: usize)->std::io::Result<()>{
+        if !bound_and_align_check(self.block_size(), self.total_size(), offset, size) {
+            return Err(ErrorKind::InvalidInput)?;
+        }else{
+            unsafe {self.unsafe_discard(offset, size).await}
+        }
+    }
+    /// Makes a range read as zeroes.
+    /// With `may_trim` the storage behind the range may be freed, as with `discard`.
+    /// With `fast_only` the provider fails with `ErrorKind::Unsupported` instead of writing out zeroes,
+    /// which is what the default implementation does.
+    async unsafe fn unsafe_write_zeroes(&mut self, offset: usize, size: usize, _may_trim: bool, fast_only: bool, write_through: bool)->std::io::Result<()>{
+        if fast_only{
+            return Err(ErrorKind::Unsupported)?;
+        }
+        let block_size=self.block_size();
+        let chunk_size=max(ZEROES_CHUNK_SIZE/block_size*block_size, block_size);
+        let zeroes=vec![0; min(chunk_size, size)];
+        let mut position=offset;
+        while position<offset+size{
+            let length=min(zeroes.len(), offset+size-position);
+            self.unsafe_write(position, &zeroes[..length], write_through).await?;
+            position+=length;
+        }
+        Ok(())
+    }
+    async fn write_zeroes(&mut self, offset: usize, size: usize, may_trim: bool, fast_only: bool, write_through: bool)->std::io::Result<()>{
+        if !bound_and_align_check(self.block_size(), self.total_size(), offset, size) {
+            return Err(ErrorKind::InvalidInput)?;
+        }else{
+            unsafe {self.unsafe_write_zeroes(offset, size, may_trim, fast_only, write_through).await}
+        }
+    }
+    /// Reports the allocation state of a range as consecutive extents starting at `offset`.
+    /// The extents may cover less than `size` bytes but never more.
+    /// Providers that cannot tell report everything as data.
+    async unsafe fn unsafe_block_status(&mut self, _offset: usize, size: usize)->std::io::Result<Vec<Extent>>{
+        Ok(vec![Extent::data(size)])
+    }
+    async fn block_status(&mut self, offset: usize, size: usize)->std::io::Result<Vec<Extent>>{
+        if !bound_and_align_check(self.block_size(), self.total_size(), offset, size) {
+            return Err(ErrorKind::InvalidInput)?;
+        }else{
+            unsafe {self.unsafe_block_status(offset, size).await}
+        }
+    }
+    /// Changes the size of the provider. Growing exposes zeroes, shrinking drops the data past the new end.
+    async fn resize(&mut self, _size: usize)->std::io::Result<()>{
+        Err(ErrorKind::Unsupported)?
+    }
+    fn capabilities(&self)->Capabilities{
+        Capabilities::default()
+    }
     async fn flush(&mut self)->std::io::Result<()>;
     fn block_size(&self)->usize;
 }
 
+/// Lets stacks assembled at runtime, e.g. from a configuration file, be used like any other provider.
+#[async_trait]
+impl CloudProvider for Box<dyn CloudProvider>{
+    fn total_size(&self)->usize{
+        (**self).total_size()
+    }
+    async unsafe fn unsafe_write(&mut self, offset: usize, buf: &[u8], write_through: bool)->std::io::Result<()>{
+        (**self).unsafe_write(offset, buf, write_through).await
+    }
+    async unsafe fn unsafe_read(&mut self, offset: usize, buf: &mut [u8])->std::io::Result<()>{
+        (**self).unsafe_read(offset, buf).await
+    }
+    async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8])->std::io::Result<()>{
+        (**self).unsafe_read_shared(offset, buf).await
+    }
+    async unsafe fn unsafe_prefetch(&mut self, offset: usize, size: usize)->std::io::Result<()>{
+        (**self).unsafe_prefetch(offset, size).await
+    }
+    async unsafe fn unsafe_discard(&mut self, offset: usize, size: usize)->std::io::Result<()>{
+        (**self).unsafe_discard(offset, size).await
+    }
+    async unsafe fn unsafe_write_zeroes(&mut self, offset: usize, size: usize, may_trim: bool, fast_only: bool, write_through: bool)->std::io::Result<()>{
+        (**self).unsafe_write_zeroes(offset, size, may_trim, fast_only, write_through).await
+    }
+    async unsafe fn unsafe_block_status(&mut self, offset: usize, size: usize)->std::io::Result<Vec<Extent>>{
+        (**self).unsafe_block_status(offset, size).await
+    }
+    async fn resize(&mut self, size: usize)->std::io::Result<()>{
+        (**self).resize(size).await
+    }
+    fn capabilities(&self)->Capabilities{
+        (**self).capabilities()
+    }
+    async fn flush(&mut self)->std::io::Result<()>{
+        (**self).flush().await
+    }
+    fn block_size(&self)->usize{
+        (**self).block_size()
+    }
+}
 #[async_trait]
 pub trait CloudProviderExt{
     fn block_index(&self, offset: usize)->usize;
     fn block_range(&self, offset: usize, size: usize)->Vec<(usize, Range<usize>, Range<usize>)>;
     fn create_block_buffer(&self)->Vec<u8>;
     async unsafe fn unsafe_read_block(&mut self, block_offset: usize, buf: &mut [u8])->std::io::Result<()>;
+    async unsafe fn unsafe_read_block_shared(&self, block_offset: usize, buf: &mut [u8])->std::io::Result<()>;
     async unsafe fn unsafe_write_block(&mut self, block_offset: usize, buf: &[u8], write_through: bool)->std::io::Result<()>;
 }
 #[async_trait]
@@ -88,56 +282,209 @@ impl<T: CloudProvider+Send> CloudProviderExt for T {
     async unsafe fn unsafe_read_block(&mut self, block_offset: usize, buf: &mut [u8])->std::io::Result<()>{
         self.unsafe_read(block_offset*self.block_size(), buf).await
     }
+    async unsafe fn unsafe_read_block_shared(&self, block_offset: usize, buf: &mut [u8])->std::io::Result<()>{
+        self.unsafe_read_shared(block_offset*self.block_size(), buf).await
+    }
     async unsafe fn unsafe_write_block(&mut self, block_offset: usize, buf: &[u8], write_through: bool)->std::io::Result<()>{
         self.unsafe_write(block_offset*self.block_size(), buf, write_through).await
     }
 }
+/// A provider that can be used by several connections at once.
+/// Every connection to an export goes through the same SharedProvider, so they all see the same cache,
+/// and a flush from any of them covers writes completed by all of them.
 #[async_trait]
 pub trait SharedProvider: Send+Sync{
     fn total_size(&self)->usize;
     async fn write(&self, offset: usize, buf: &[u8], write_through: bool)->std::io::Result<()>;
     async fn read(&self, offset: usize, buf: &mut [u8])->std::io::Result<()>;
+    async fn prefetch(&self, offset: usize, size: usize)->std::io::Result<()>;
+    async fn discard(&self, offset: usize, size: usize)->std::io::Result<()>;
+    async fn write_zeroes(&self, offset: usize, size: usize, may_trim: bool, fast_only: bool, write_through: bool)->std::io::Result<()>;
+    async fn block_status(&self, offset: usize, size: usize)->std::io::Result<Vec<Extent>>;
+    async fn resize(&self, size: usize)->std::io::Result<()>;
+    fn capabilities(&self)->Capabilities;
     async fn flush(&self)->std::io::Result<()>;
     fn block_size(&self)->usize;
 }
-pub struct MutexProvider<T: CloudProvider+Sized>{
-    provider: Mutex<Box<T>>,
+/// Shares a provider behind a read-write lock. Reads of providers with `shared_read` take the lock
+/// shared, so that pipelined reads wait on the backend together; everything else takes it exclusively.
+pub struct LockedProvider<T: CloudProvider+Sized>{
+    provider: RwLock<Box<T>>,
     block_size: usize,
-    total_size: usize
+    /// Kept in sync with the provider on resize, so that it can be read without locking.
+    total_size: AtomicUsize,
+    capabilities: Capabilities
 }
 
-impl<T: CloudProvider+Send+Sync> MutexProvider<T>{
+impl<T: CloudProvider+Send+Sync> LockedProvider<T>{
     pub fn new(provider: T)->Self{
-        let (block_size, total_size)=(provider.block_size(), provider.total_size());
-        MutexProvider{
-            provider: Mutex::new(Box::new(provider)),
+        let (block_size, total_size, capabilities)=(provider.block_size(), provider.total_size(), provider.capabilities());
+        LockedProvider{
+            provider: RwLock::new(Box::new(provider)),
             block_size,
-            total_size
+            total_size: AtomicUsize::new(total_size),
+            capabilities
         }
     }
-    pub fn mutex(&self)->&Mutex<Box<T>>{
+    pub fn lock(&self)->&RwLock<Box<T>>{
         &self.provider
     }
 }
 #[async_trait]
-impl<T: CloudProvider + Send+Sync+Sized> SharedProvider for MutexProvider<T>{
+impl<T: CloudProvider + Send+Sync+Sized> SharedProvider for LockedProvider<T>{
     fn total_size(&self)->usize{
-        self.total_size
+        self.total_size.load(Ordering::SeqCst)
     }
     async fn write(&self, offset: usize, buf: &[u8], write_through: bool)->std::io::Result<()>{
-        let mut lock=self.provider.lock().await;
+        let mut lock=self.provider.write().await;
         lock.write(offset, buf, write_through).await
     }
     async fn read(&self, offset: usize, buf: &mut [u8])->std::io::Result<()>{
-        let mut lock=self.provider.lock().await;
-        lock.read(offset, buf).await
+        if self.capabilities.shared_read{
+            let lock=self.provider.read().await;
+            lock.read_shared(offset, buf).await
+        }else{
+            let mut lock=self.provider.write().await;
+            lock.read(offset, buf).await
+        }
+    }
+    async fn prefetch(&self, offset: usize, size: usize)->std::io::Result<()>{
+        let mut lock=self.provider.write().await;
+        lock.prefetch(offset, size).await
+    }
+    async fn discard(&self, offset: usize, size: usize)->std::io::Result<()>{
+        let mut lock=self.provider.write().await;
+        lock.discard(offset, size).await
+    }
+    async fn write_zeroes(&self, offset: usize, size: usize, may_trim: bool, fast_only: bool, write_through: bool)->std::io::Result<()>{
+        let mut lock=self.provider.write().await;
+        lock.write_zeroes(offset, size, may_trim, fast_only, write_through).await
+    }
+    async fn block_status(&self, offset: usize, size: usize)->std::io::Result<Vec<Extent>>{
+        let mut lock=self.provider.write().await;
+        lock.block_status(offset, size).await
+    }
+    async fn resize(&self, size: usize)->std::io::Result<()>{
+        let mut lock=self.provider.write().await;
+        let result=lock.resize(size).await;
+        self.total_size.store(lock.total_size(), Ordering::SeqCst);
+        result
+    }
+    fn capabilities(&self)->Capabilities{
+        self.capabilities
     }
     async fn flush(&self)->std::io::Result<()>{
-        let mut lock=self.provider.lock().await;
+        let mut lock=self.provider.write().await;
         lock.flush().await
     }
     fn block_size(&self)->usize{
         self.block_size
     }
 
-}
\ No newline at end of file
+}
+
+/// Runs a test in a task of the threaded runtime, since providers calling `block_in_place` need one.
+#[cfg(test)]
+pub fn run_in_task(test: impl std::future::Future<Output=()>+Send+'static){
+    let mut runtime=tokio::runtime::Builder::new().threaded_scheduler().enable_all().build().unwrap();
+    runtime.block_on(async {tokio::spawn(test).await.unwrap()});
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use tokio::sync::Barrier;
+    use tokio::time::{timeout, Duration};
+
+    /// Shared reads only complete once two of them wait at the same time.
+    struct Rendezvous{
+        barrier: Barrier,
+        shared_read: bool
+    }
+    #[async_trait]
+    impl CloudProvider for Rendezvous{
+        fn total_size(&self)->usize{
+            1<<20
+        }
+        async unsafe fn unsafe_write(&mut self, _offset: usize, _buf: &[u8], _write_through: bool)->std::io::Result<()>{
+            Ok(())
+        }
+        async unsafe fn unsafe_read(&mut self, _offset: usize, buf: &mut [u8])->std::io::Result<()>{
+            copy_padded(buf, &[]);
+            Ok(())
+        }
+        async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8])->std::io::Result<()>{
+            self.barrier.wait().await;
+            copy_padded(buf, &[(offset/4096) as u8]);
+            Ok(())
+        }
+        fn capabilities(&self)->Capabilities{
+            Capabilities{shared_read: self.shared_read, ..Capabilities::default()}
+        }
+        async fn flush(&mut self)->std::io::Result<()>{
+            Ok(())
+        }
+        fn block_size(&self)->usize{
+            4096
+        }
+    }
+
+    #[tokio::test]
+    async fn overlaps_shared_reads(){
+        let provider=LockedProvider::new(Rendezvous{barrier: Barrier::new(2), shared_read: true});
+        let (mut first, mut second)=(vec![9; 4096], vec![9; 4096]);
+        let reads=async {tokio::join!(provider.read(4096, &mut first), provider.read(8192, &mut second))};
+        let (first_result, second_result)=timeout(Duration::from_secs(5), reads).await.expect("reads did not overlap");
+        first_result.unwrap();
+        second_result.unwrap();
+        assert_eq!(first[0], 1);
+        assert_eq!(second[0], 2);
+        assert!(first[1..].iter().chain(second[1..].iter()).all(|byte| *byte==0));
+    }
+
+    #[tokio::test]
+    async fn reads_exclusively_without_shared_read(){
+        let provider=LockedProvider::new(Rendezvous{barrier: Barrier::new(2), shared_read: false});
+        let mut buf=vec![9; 4096];
+        timeout(Duration::from_secs(5), provider.read(4096, &mut buf)).await.unwrap().unwrap();
+        assert!(buf.iter().all(|byte| *byte==0));
+    }
+
+    #[test]
+    fn checks_bounds_and_alignment(){
+        assert!(bound_and_align_check(4096, 1<<20, 0, 1<<20));
+        assert!(bound_and_align_check(4096, 1<<20, 4096, 8192));
+        assert!(!bound_and_align_check(4096, 1<<20, 512, 4096));
+        assert!(!bound_and_align_check(4096, 1<<20, 4096, 512));
+        assert!(!bound_and_align_check(4096, 1<<20, 1<<20, 4096));
+        assert!(!bound_and_align_check(4096, 1<<20, (1<<20)-4096, 8192));
+    }
+
+    #[test]
+    fn rejects_ranges_that_overflow(){
+        assert!(!bound_and_align_check(4096, 1<<20, 4096, 0usize.wrapping_sub(4096)));
+        assert!(!bound_and_align_check(1, 1<<20, 1, usize::MAX));
+    }
+
+    #[test]
+    fn merges_extents_of_the_same_state(){
+        let mut extents=Vec::new();
+        push_extent(&mut extents, Extent::data(4096));
+        push_extent(&mut extents, Extent::data(4096));
+        push_extent(&mut extents, Extent::hole(512));
+        push_extent(&mut extents, Extent::hole(512));
+        push_extent(&mut extents, Extent{length: 1024, hole: false, zero: true});
+        push_extent(&mut extents, Extent::data(1));
+        assert_eq!(extents, [Extent::data(8192), Extent::hole(1024), Extent{length: 1024, hole: false, zero: true}, Extent::data(1)]);
+    }
+
+    #[test]
+    fn skips_empty_extents(){
+        let mut extents=Vec::new();
+        push_extent(&mut extents, Extent::hole(0));
+        assert!(extents.is_empty());
+        push_extent(&mut extents, Extent::data(4096));
+        push_extent(&mut extents, Extent::hole(0));
+        push_extent(&mut extents, Extent::data(4096));
+        assert_eq!(extents, [Extent::data(8192)]);
+    }
+}
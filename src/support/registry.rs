@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use async_trait::async_trait;
+use super::CloudProvider;
+
+#[derive(Debug)]
+pub enum RegistryError{
+    Syntax(String),
+    UnknownBackend(String),
+    UnknownLayer(String),
+    MissingArgument(String),
+    BadArgument(String)
+}
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self{
+            RegistryError::Syntax(spec)=>write!(f, "Bad provider spec {:?}", spec),
+            RegistryError::UnknownBackend(name)=>write!(f, "Unknown backend {}", name),
+            RegistryError::UnknownLayer(name)=>write!(f, "Unknown layer {}", name),
+            RegistryError::MissingArgument(argument)=>write!(f, "Missing {}", argument),
+            RegistryError::BadArgument(argument)=>write!(f, "Bad argument {}", argument)
+        }
+    }
+}
+impl std::error::Error for RegistryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // Generic error, underlying cause isn't tracked.
+        None
+    }
+}
+pub type Result<T>=std::result::Result<T, RegistryError>;
+
+/// One component of a provider spec, such as `lru(cap=1024)` or `seafile://server/library`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderSpec{
+    pub name: String,
+    pub args: BTreeMap<String, String>,
+    /// Everything after `://`, only allowed on the last component.
+    pub location: Option<String>
+}
+impl ProviderSpec{
+    fn parse(component: &str)->Result<ProviderSpec>{
+        let syntax_error=|| RegistryError::Syntax(String::from(component));
+        // Arguments may contain `://` themselves, e.g. an endpoint URL, so the location is looked for after them.
+        let (name, args, rest)=match (component.find('('), component.find("://")){
+            (Some(open), separator) if separator.map(|separator| open<separator).unwrap_or(true)=>{
+                let close=open+component[open..].find(')').ok_or_else(syntax_error)?;
+                (&component[..open], &component[open+1..close], &component[close+1..])
+            }
+            (_, Some(separator))=>(&component[..separator], "", &component[separator..]),
+            (_, None)=>(component, "", "")
+        };
+        let location=match rest{
+            ""=>None,
+            rest if rest.starts_with("://")=>Some(String::from(&rest[3..])),
+            _=>return Err(syntax_error())
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c=='_' || c=='-'){
+            return Err(syntax_error());
+        }
+        let mut parsed_args=BTreeMap::new();
+        for arg in args.split(',').filter(|arg| !arg.is_empty()){
+            match arg.find('='){
+                Some(index)=>{
+                    parsed_args.insert(String::from(arg[..index].trim()), String::from(arg[index+1..].trim()));
+                }
+                None=>return Err(syntax_error())
+            }
+        }
+        Ok(ProviderSpec{name: String::from(name), args: parsed_args, location})
+    }
+    pub fn arg<T: FromStr>(&self, key: &str)->Result<Option<T>>{
+        match self.args.get(key){
+            Some(value)=>value.parse::<T>().map(Some).map_err(|_| RegistryError::BadArgument(format!("{}.{}={}", self.name, key, value))),
+            None=>Ok(None)
+        }
+    }
+    pub fn required_arg<T: FromStr>(&self, key: &str)->Result<T>{
+        self.arg(key)?.ok_or_else(|| RegistryError::MissingArgument(format!("{}.{}", self.name, key)))
+    }
+    pub fn location(&self)->Result<&str>{
+        self.location.as_deref().ok_or_else(|| RegistryError::MissingArgument(format!("{}://", self.name)))
+    }
+    /// An argument holding a size, which takes a K, M, G or T suffix.
+    pub fn size_arg(&self, key: &str)->Result<usize>{
+        match self.args.get(key){
+            Some(value)=>parse_size(value).ok_or_else(|| RegistryError::BadArgument(format!("{}.{}={}", self.name, key, value))),
+            None=>Err(RegistryError::MissingArgument(format!("{}.{}", self.name, key)))
+        }
+    }
+    /// The `size` argument, falling back to the size given by the caller.
+    pub fn size(&self, size: Option<usize>)->Result<usize>{
+        match size{
+            Some(size) if !self.args.contains_key("size")=>Ok(size),
+            _=>self.size_arg("size")
+        }
+    }
+}
+impl fmt::Display for ProviderSpec{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.args.is_empty(){
+            let args: Vec<String>=self.args.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+            write!(f, "({})", args.join(","))?;
+        }
+        if let Some(location)=&self.location{
+            write!(f, "://{}", location)?;
+        }
+        Ok(())
+    }
+}
+pub fn parse_size(value: &str)->Option<usize>{
+    let (digits, unit)=match value.chars().last()?.to_ascii_uppercase(){
+        'K'=>(&value[..value.len()-1], 1usize<<10),
+        'M'=>(&value[..value.len()-1], 1<<20),
+        'G'=>(&value[..value.len()-1], 1<<30),
+        'T'=>(&value[..value.len()-1], 1<<40),
+        _=>(value, 1)
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+/// Splits a spec on the `+` between components, leaving those inside arguments or the location alone.
+fn split_spec(spec: &str)->Result<Vec<&str>>{
+    let mut components=Vec::new();
+    let (mut depth, mut start)=(0, 0);
+    for (index, c) in spec.char_indices(){
+        match c{
+            '('=>depth+=1,
+            ')' if depth>0=>depth-=1,
+            ')'=>return Err(RegistryError::Syntax(String::from(spec))),
+            ':' if depth==0 && spec[index..].starts_with("://")=>break,
+            '+' if depth==0=>{
+                components.push(&spec[start..index]);
+                start=index+1;
+            }
+            _=>{}
+        }
+    }
+    if depth>0{
+        return Err(RegistryError::Syntax(String::from(spec)));
+    }
+    components.push(&spec[start..]);
+    Ok(components)
+}
+
+/// Creates the innermost provider of a stack, which holds the actual data.
+#[async_trait]
+pub trait BackendFactory: Send+Sync{
+    async fn create(&self, spec: &ProviderSpec, size: Option<usize>)->std::result::Result<Box<dyn CloudProvider>, Box<dyn Error>>;
+}
+/// Wraps a provider into another one, e.g. a cache.
+pub trait LayerFactory: Send+Sync{
+    fn wrap(&self, spec: &ProviderSpec, provider: Box<dyn CloudProvider>)->std::result::Result<Box<dyn CloudProvider>, Box<dyn Error>>;
+}
+/// Builds provider stacks from spec strings such as `bytes+lru(cap=1024)+seafile://server/library`.
+/// Components are listed outermost first, and the last one is the backend.
+pub struct Registry{
+    backends: BTreeMap<String, Box<dyn BackendFactory>>,
+    layers: BTreeMap<String, Box<dyn LayerFactory>>
+}
+impl Registry{
+    pub fn new()->Self{
+        Registry{
+            backends: BTreeMap::new(),
+            layers: BTreeMap::new()
+        }
+    }
+    pub fn register_backend<T: BackendFactory+'static>(&mut self, name: &str, factory: T){
+        self.backends.insert(String::from(name), Box::new(factory));
+    }
+    pub fn register_layer<T: LayerFactory+'static>(&mut self, name: &str, factory: T){
+        self.layers.insert(String::from(name), Box::new(factory));
+    }
+    pub fn parse(spec: &str)->Result<Vec<ProviderSpec>>{
+        let components=split_spec(spec)?;
+        let specs=components.iter().map(|component| ProviderSpec::parse(component)).collect::<Result<Vec<_>>>()?;
+        if specs[..specs.len()-1].iter().any(|spec| spec.location.is_some()){
+            return Err(RegistryError::Syntax(String::from(spec)));
+        }
+        Ok(specs)
+    }
+    /// Builds the stack described by `spec`. Backends use `size` unless the spec gives its own.
+    pub async fn build(&self, spec: &str, size: Option<usize>)->std::result::Result<Box<dyn CloudProvider>, Box<dyn Error>>{
+        self.build_stack(&Registry::parse(spec)?, size).await
+    }
+    /// Builds a stack from components that are already parsed, outermost first.
+    pub async fn build_stack(&self, specs: &[ProviderSpec], size: Option<usize>)->std::result::Result<Box<dyn CloudProvider>, Box<dyn Error>>{
+        let (backend_spec, layer_specs)=specs.split_last().ok_or_else(|| RegistryError::Syntax(String::new()))?;
+        let backend=self.backends.get(&backend_spec.name).ok_or_else(|| RegistryError::UnknownBackend(backend_spec.name.clone()))?;
+        let mut provider=backend.create(backend_spec, size).await?;
+        for layer_spec in layer_specs.iter().rev(){
+            let layer=self.layers.get(&layer_spec.name).ok_or_else(|| RegistryError::UnknownLayer(layer_spec.name.clone()))?;
+            provider=layer.wrap(layer_spec, provider)?;
+        }
+        Ok(provider)
+    }
+}
+impl Default for Registry{
+    /// A registry with every provider shipped with clouddrive.
+    fn default()->Self{
+        let mut registry=Registry::new();
+        super::memory::register(&mut registry);
+        super::file::register(&mut registry);
+        super::directory::register(&mut registry);
+        super::seafile::register(&mut registry);
+        super::s3::register(&mut registry);
+        super::webdav::register(&mut registry);
+        super::http::register(&mut registry);
+        super::byte::register(&mut registry);
+        super::lru::register(&mut registry);
+        super::cow::register(&mut registry);
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn args(pairs: &[(&str, &str)])->BTreeMap<String, String>{
+        pairs.iter().map(|(key, value)| (String::from(*key), String::from(*value))).collect()
+    }
+
+    #[test]
+    fn parses_components(){
+        assert_eq!(ProviderSpec::parse("memory").unwrap(), ProviderSpec{name: String::from("memory"), args: BTreeMap::new(), location: None});
+        let spec=ProviderSpec::parse("directory(size=1G, block=4K):///var/lib/volume").unwrap();
+        assert_eq!(spec.name, "directory");
+        assert_eq!(spec.args, args(&[("size", "1G"), ("block", "4K")]));
+        assert_eq!(spec.location.as_deref(), Some("/var/lib/volume"));
+        assert_eq!(ProviderSpec::parse("seafile://server/library").unwrap().location.as_deref(), Some("server/library"));
+    }
+
+    #[test]
+    fn finds_location_after_arguments(){
+        let spec=ProviderSpec::parse("s3(endpoint=http://localhost:9000,region=us-east-1)://bucket/prefix/").unwrap();
+        assert_eq!(spec.name, "s3");
+        assert_eq!(spec.args, args(&[("endpoint", "http://localhost:9000"), ("region", "us-east-1")]));
+        assert_eq!(spec.location.as_deref(), Some("bucket/prefix/"));
+        // Parentheses in the location are not arguments.
+        let spec=ProviderSpec::parse("file:///images/disk(1).raw").unwrap();
+        assert!(spec.args.is_empty());
+        assert_eq!(spec.location.as_deref(), Some("/images/disk(1).raw"));
+    }
+
+    #[test]
+    fn rejects_bad_components(){
+        for component in ["", "lru(cap=1", "lru(cap)", "lru(cap=1)x", "l ru", "(cap=1)", "://path"].iter(){
+            assert!(ProviderSpec::parse(component).is_err(), "{}", component);
+        }
+    }
+
+    #[test]
+    fn splits_stacks(){
+        let specs=Registry::parse("bytes+lru(cap=1024)+memory(size=1G)").unwrap();
+        let names: Vec<&str>=specs.iter().map(|spec| spec.name.as_str()).collect();
+        assert_eq!(names, ["bytes", "lru", "memory"]);
+        assert_eq!(specs[1].args, args(&[("cap", "1024")]));
+        // `+` in arguments and locations does not start a component.
+        let specs=Registry::parse("cow+webdav(password=a+b)://server/a+b/").unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[1].args, args(&[("password", "a+b")]));
+        assert_eq!(specs[1].location.as_deref(), Some("server/a+b/"));
+        assert!(Registry::parse("file:///a+memory").unwrap().len()==1);
+        assert!(Registry::parse("lru(cap=1))+memory").is_err());
+        assert!(Registry::parse("lru(cap=(1)+memory").is_err());
+    }
+
+    #[test]
+    fn location_ends_the_stack(){
+        let specs=Registry::parse("cow://x+memory").unwrap();
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].location.as_deref(), Some("x+memory"));
+    }
+
+    #[test]
+    fn displays_as_parsed(){
+        for spec in ["memory", "lru(cap=1024)", "s3(endpoint=http://localhost:9000,region=eu-west-1)://bucket/prefix/"].iter(){
+            assert_eq!(ProviderSpec::parse(spec).unwrap().to_string(), *spec);
+        }
+    }
+
+    #[test]
+    fn names_the_culprit(){
+        assert_eq!(Registry::parse("lru(cap=1+memory").unwrap_err().to_string(), "Bad provider spec \"lru(cap=1+memory\"");
+        let spec=Registry::parse("lru(cap=x)").unwrap().remove(0);
+        assert_eq!(spec.required_arg::<usize>("cap").unwrap_err().to_string(), "Bad argument lru.cap=x");
+        assert_eq!(spec.location().unwrap_err().to_string(), "Missing lru://");
+    }
+
+    #[test]
+    fn reads_arguments(){
+        let spec=ProviderSpec::parse("memory(size=2M, cap=x)").unwrap();
+        assert_eq!(spec.size(None).unwrap(), 2<<20);
+        assert_eq!(spec.size(Some(4096)).unwrap(), 2<<20);
+        assert!(spec.arg::<usize>("cap").is_err());
+        assert_eq!(spec.arg::<usize>("block").unwrap(), None);
+        assert!(spec.required_arg::<usize>("block").is_err());
+        assert!(spec.location().is_err());
+        let spec=ProviderSpec::parse("memory").unwrap();
+        assert_eq!(spec.size(Some(4096)).unwrap(), 4096);
+        assert!(spec.size(None).is_err());
+    }
+
+    #[test]
+    fn parses_sizes(){
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("4K"), Some(4096));
+        assert_eq!(parse_size("64k"), Some(64<<10));
+        assert_eq!(parse_size("1M"), Some(1<<20));
+        assert_eq!(parse_size("2G"), Some(2<<30));
+        assert_eq!(parse_size("1T"), Some(1<<40));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("12X"), None);
+        assert_eq!(parse_size("99999999999999999999T"), None);
+        assert_eq!(parse_size(&format!("{}T", usize::MAX)), None);
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt::Debug;
+use std::future::Future;
+use tokio::time::{delay_for, Duration};
+
+/// Attempts made for each request before its error is passed on to the client.
+pub const MAX_ATTEMPTS:usize=5;
+/// Wait before the first retry, doubled after every failed attempt.
+const INITIAL_BACKOFF:Duration=Duration::from_millis(200);
+
+/// Errors of remote backends that can tell whether trying again might help.
+pub trait Transient{
+    fn is_transient(&self)->bool;
+}
+/// Whether a request answered with `status` is worth retrying. Other client errors will not go away by themselves,
+/// but a conflict may be resolved by the time we retry, and rate limiting passes.
+pub fn transient_status(status: u16)->bool{
+    status>=500 || status==409 || status==429
+}
+/// Runs `attempt` until it succeeds, fails with an error that is not transient, or MAX_ATTEMPTS are used up,
+/// backing off exponentially in between. `what` names the request in the log.
+pub async fn retry<T, E, F, Fut>(what: &str, mut attempt: F)->Result<T, E>
+    where F: FnMut()->Fut, Fut: Future<Output=Result<T, E>>, E: Transient+Debug{
+    let mut backoff=INITIAL_BACKOFF;
+    for retries in 1..MAX_ATTEMPTS{
+        match attempt().await{
+            Err(err) if err.is_transient()=>{
+                eprintln!("{} error {:?}, starting retry #{} in {:?}...", what, err, retries, backoff);
+                delay_for(backoff).await;
+                backoff*=2;
+            }
+            result=>return result
+        }
+    }
+    attempt().await
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::cell::Cell;
+
+    #[derive(Debug)]
+    struct Status(u16);
+    impl Transient for Status{
+        fn is_transient(&self)->bool{
+            transient_status(self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors(){
+        let attempts=Cell::new(0);
+        let result=retry("test", || async {
+            attempts.set(attempts.get()+1);
+            if attempts.get()<3 {Err(Status(503))} else {Ok(attempts.get())}
+        }).await;
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_client_errors(){
+        let attempts=Cell::new(0);
+        let result: Result<(), Status>=retry("test", || async {
+            attempts.set(attempts.get()+1);
+            Err(Status(404))
+        }).await;
+        assert_eq!(result.unwrap_err().0, 404);
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn tells_transient_statuses(){
+        for status in [409, 429, 500, 502, 503].iter(){
+            assert!(transient_status(*status), "{}", status);
+        }
+        for status in [400, 401, 403, 404, 405, 416].iter(){
+            assert!(!transient_status(*status), "{}", status);
+        }
+    }
+}
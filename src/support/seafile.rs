@@ -2,9 +2,11 @@ use reqwest::*;
 use tokio::prelude::*;
 use async_trait::async_trait;
 use std::ops::Range;
-use crate::support::{CloudProvider, CloudProviderExt};
+use crate::support::{Capabilities, CloudProvider, CloudProviderExt, Extent, copy_padded, push_extent};
+use crate::support::registry::{BackendFactory, ProviderSpec, Registry, RegistryError};
+use crate::support::retry::{retry, transient_status, Transient};
 use std::io::ErrorKind;
-use bytes::Buf;
+use std::collections::BTreeSet;
 use std::fmt;
 
 pub const BLOCK_SIZE:usize=1*1024;
@@ -12,7 +14,11 @@ pub struct SeafileProvider {
     total_size: usize,
     http: Client,
     token: String,
-    library_path: String
+    library_path: String,
+    /// Root of the Seafile web API, e.g. `https://cloud.tsinghua.edu.cn/api2/`.
+    api_base: String,
+    /// Blocks known to exist in the library, loaded on the first allocation query.
+    allocated_blocks: Option<BTreeSet<usize>>
 }
 #[derive(Debug)]
 pub enum SeafileError{
@@ -39,23 +45,42 @@ impl From<SeafileError> for std::io::Error{
         std::io::Error::new(ErrorKind::Other, e)
     }
 }
+impl Transient for SeafileError{
+    fn is_transient(&self)->bool{
+        match self{
+            SeafileError::IOError(_)=>true,
+            SeafileError::BadResponseError(status)=>transient_status(*status),
+            _=>false
+        }
+    }
+}
 const SEAFILE_API_BASE: &str="https://cloud.tsinghua.edu.cn/api2/";
 const SEAFILE_AUTH_PING: &str="https://cloud.tsinghua.edu.cn/api2/ping/";
 const SEAFILE_LIBRARY_BASE:&str="https://cloud.tsinghua.edu.cn/api2/repos/{}/";
 const SEAFILE_LIBRARY_FILE:&str="https://cloud.tsinghua.edu.cn/api2/repos/{}/file/?p=/{}.block";
 const SEAFILE_LIBRARY_UPLOAD_LINK:&str="https://cloud.tsinghua.edu.cn/api2/repos/{}/upload-link/";
-fn seafile_library_base(library: &str)->String{
-    format!("https://cloud.tsinghua.edu.cn/api2/repos/{}/", library)
+fn seafile_auth_ping(api_base: &str)->String{
+    format!("{}ping/", api_base)
+}
+fn seafile_library_base(api_base: &str, library: &str)->String{
+    format!("{}repos/{}/", api_base, library)
+}
+fn seafile_library_file(api_base: &str, library: &str, file: usize)->String{
+    format!("{}repos/{}/file/?p=/{}.block", api_base, library, file)
 }
-fn seafile_library_file(library: &str, file: usize)->String{
-    format!("https://cloud.tsinghua.edu.cn/api2/repos/{}/file/?p=/{}.block", library, file)
+fn seafile_library_dir(api_base: &str, library: &str)->String{
+    format!("{}repos/{}/dir/?p=/", api_base, library)
 }
-fn seafile_library_upload_link(library: &str)->String{
-    format!("https://cloud.tsinghua.edu.cn/api2/repos/{}/upload-link/", library)
+fn seafile_library_upload_link(api_base: &str, library: &str)->String{
+    format!("{}repos/{}/upload-link/", api_base, library)
 }
 pub type Result<T>=std::result::Result<T, SeafileError>;
 impl SeafileProvider{
     pub async fn connect(token: &str, library: &str, total_size: usize)->Result<Self>{
+        Self::connect_to(SEAFILE_API_BASE, token, library, total_size).await
+    }
+    /// Connects to a library on another Seafile server, given the root of its web API.
+    pub async fn connect_to(api_base: &str, token: &str, library: &str, total_size: usize)->Result<Self>{
         if total_size % BLOCK_SIZE !=0{
             return Err(SeafileError::BadTotalSizeError);
         }
@@ -73,16 +98,18 @@ impl SeafileProvider{
             total_size,
             http: client_builder.build().unwrap(),
             token: String::from(token),
-            library_path: String::from(library)
+            library_path: String::from(library),
+            api_base: String::from(api_base),
+            allocated_blocks: None
         };
         seafile.ping().await?;
         Ok(seafile)
     }
     async fn ping(&mut self)->Result<()>{
-        match self.http.get(SEAFILE_AUTH_PING).send().await{
+        match self.http.get(&seafile_auth_ping(&self.api_base)).send().await{
             Ok(response)=>{
                 if response.status()==reqwest::StatusCode::OK{
-                    match self.http.get(&seafile_library_base(&self.library_path)).send().await {
+                    match self.http.get(&seafile_library_base(&self.api_base, &self.library_path)).send().await {
                         Ok(response)=>{
                             if response.status()==reqwest::StatusCode::OK{
                                 Ok(())
@@ -99,8 +126,9 @@ impl SeafileProvider{
             Err(error)=>Err(SeafileError::IOError(Box::new(error)))
         }
     }
-    async fn get_block(&mut self, block_id: usize, buf: &mut [u8])->Result<()>{
-        match self.http.get(&seafile_library_file(&self.library_path, block_id)).send().await{
+    /// Downloads a block, which comes back empty if it does not exist.
+    async fn get_block(&self, block_id: usize)->Result<bytes::Bytes>{
+        match self.http.get(&seafile_library_file(&self.api_base, &self.library_path, block_id)).send().await{
             Ok(response)=>{
                 if response.status()==reqwest::StatusCode::OK{
                     let text:String=response.text().await.unwrap();
@@ -108,17 +136,27 @@ impl SeafileProvider{
                     match self.http.get(url).send().await{
                         Ok(response)=>{
                             match response.bytes().await{
-                                Ok(mut buffer)=>{
-                                    buffer.copy_to_slice(&mut buf[0..buffer.remaining()]);
-                                    Ok(())
-                                }
+                                Ok(buffer)=>Ok(buffer),
                                 Err(error)=>Err(SeafileError::IOError(Box::new(error)))
                             }
                         }
                         Err(error)=>Err(SeafileError::IOError(Box::new(error)))
                     }
                 }else if response.status()==reqwest::StatusCode::NOT_FOUND{
-                    // considered as uninitialized chunks.
+                    // considered as uninitialized chunks, which read as zeroes.
+                    Ok(bytes::Bytes::new())
+                }else {
+                    Err(SeafileError::BadResponseError(response.status().as_u16()))
+                }
+            }
+            Err(error)=>Err(SeafileError::IOError(Box::new(error)))
+        }
+    }
+    async fn delete_block(&self, block_id: usize)->Result<()>{
+        match self.http.delete(&seafile_library_file(&self.api_base, &self.library_path, block_id)).send().await{
+            Ok(response)=>{
+                // A missing block is as good as a deleted one.
+                if response.status()==reqwest::StatusCode::OK || response.status()==reqwest::StatusCode::NOT_FOUND{
                     Ok(())
                 }else {
                     Err(SeafileError::BadResponseError(response.status().as_u16()))
@@ -127,8 +165,35 @@ impl SeafileProvider{
             Err(error)=>Err(SeafileError::IOError(Box::new(error)))
         }
     }
-    async fn put_block(&mut self, block_id: usize, buf: &[u8])->Result<()>{
-        match self.http.get(&seafile_library_upload_link(&self.library_path)).send().await{
+    async fn list_blocks(&self)->Result<BTreeSet<usize>>{
+        match self.http.get(&seafile_library_dir(&self.api_base, &self.library_path)).send().await{
+            Ok(response)=>{
+                if response.status()==reqwest::StatusCode::OK{
+                    match response.json::<serde_json::Value>().await{
+                        Ok(entries)=>{
+                            let blocks=entries.as_array().map(|entries| entries.iter().filter_map(|entry| {
+                                entry["name"].as_str()?.strip_suffix(".block")?.parse::<usize>().ok()
+                            }).collect()).unwrap_or_default();
+                            Ok(blocks)
+                        }
+                        Err(error)=>Err(SeafileError::IOError(Box::new(error)))
+                    }
+                }else {
+                    Err(SeafileError::BadResponseError(response.status().as_u16()))
+                }
+            }
+            Err(error)=>Err(SeafileError::IOError(Box::new(error)))
+        }
+    }
+    async fn load_allocated_blocks(&mut self)->Result<()>{
+        if self.allocated_blocks.is_none(){
+            let this=&*self;
+            self.allocated_blocks=Some(retry("Seafile list_blocks", || this.list_blocks()).await?);
+        }
+        Ok(())
+    }
+    async fn put_block(&self, block_id: usize, buf: &[u8])->Result<()>{
+        match self.http.get(&seafile_library_upload_link(&self.api_base, &self.library_path)).send().await{
             Ok(response)=>{
                 if response.status()==reqwest::StatusCode::OK{
                     let text:String=response.text().await.unwrap();
@@ -137,9 +202,7 @@ impl SeafileProvider{
                     let part=multipart::Part::bytes(copied_bytes).file_name(format!("{}.block", block_id));
                     let form = reqwest::multipart::Form::new().text("parent_dir","/").text("replace","1").part("file",part);
                     match self.http.post(url).multipart(form).send().await{
-                        Ok(response)=>{
-                            Ok(())
-                        }
+                        Ok(_response)=>Ok(()),
                         Err(error)=>Err(SeafileError::IOError(Box::new(error)))
                     }
                 }else if response.status()==reqwest::StatusCode::NOT_FOUND{
@@ -152,6 +215,27 @@ impl SeafileProvider{
             Err(error)=>Err(SeafileError::IOError(Box::new(error)))
         }
     }
+    async fn put_block_with_retries(&mut self, block_id: usize, buf: &[u8])->Result<()>{
+        let this=&*self;
+        retry("Seafile put_block", || this.put_block(block_id, buf)).await?;
+        if let Some(blocks)=&mut self.allocated_blocks{
+            blocks.insert(block_id);
+        }
+        Ok(())
+    }
+    async fn get_block_with_retries(&self, block_id: usize, buf: &mut [u8])->Result<()>{
+        let bytes=retry("Seafile get_block", || self.get_block(block_id)).await?;
+        copy_padded(buf, &bytes);
+        Ok(())
+    }
+    async fn delete_block_with_retries(&mut self, block_id: usize)->Result<()>{
+        let this=&*self;
+        retry("Seafile delete_block", || this.delete_block(block_id)).await?;
+        if let Some(blocks)=&mut self.allocated_blocks{
+            blocks.remove(&block_id);
+        }
+        Ok(())
+    }
 }
 #[async_trait]
 impl CloudProvider for SeafileProvider {
@@ -164,41 +248,90 @@ impl CloudProvider for SeafileProvider {
         let block_size=self.block_size();
         println!("Write {} {}", offset, buf.len());
         for (block_id, _range_block, range_local) in range.iter(){
-            let mut retries=0;
-            'retry:loop{
-                match self.put_block(*block_id, &buf[Range::clone(range_local)]).await{
-                    Ok(())=>{break'retry;}
-                    Err(err)=>{
-                        retries+=1;
-                        eprintln!("Seafile put_block error {:?}, starting retry #{}...", err, retries);
-                    }
-                }
-            }
+            self.put_block_with_retries(*block_id, &buf[Range::clone(range_local)]).await?;
         }
         println!("Write {} {} done", offset, buf.len());
         Ok(())
     }
 
     async unsafe fn unsafe_read(&mut self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        self.unsafe_read_shared(offset, buf).await
+    }
+
+    async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
         println!("Read {} {}", offset, buf.len());
         let range=self.block_range(offset, buf.len());
         let block_size=self.block_size();
         for (block_id, _range_block, range_local) in range.iter(){
-            let mut retries=0;
-            'retry:loop {
-                match self.get_block(*block_id, &mut buf[Range::clone(range_local)]).await{
-                    Ok(())=>{break'retry;}
-                    Err(err)=>{
-                        retries+=1;
-                        eprintln!("Seafile put_block error {:?}, starting retry #{}...", err, retries);
-                    }
-                }
-            }
+            self.get_block_with_retries(*block_id, &mut buf[Range::clone(range_local)]).await?;
         }
         println!("Read {} {} done", offset, buf.len());
         Ok(())
     }
 
+    async unsafe fn unsafe_discard(&mut self, offset: usize, size: usize) -> std::io::Result<()> {
+        println!("Discard {} {}", offset, size);
+        let range=self.block_range(offset, size);
+        for (block_id, _range_block, _range_local) in range.iter(){
+            self.delete_block_with_retries(*block_id).await?;
+        }
+        println!("Discard {} {} done", offset, size);
+        Ok(())
+    }
+
+    async unsafe fn unsafe_write_zeroes(&mut self, offset: usize, size: usize, may_trim: bool, fast_only: bool, write_through: bool) -> std::io::Result<()> {
+        if may_trim{
+            // Missing blocks read as zeroes.
+            self.unsafe_discard(offset, size).await
+        }else if fast_only{
+            Err(ErrorKind::Unsupported)?
+        }else{
+            let range=self.block_range(offset, size);
+            let zeroes=vec![0; self.block_size()];
+            for (block_id, _range_block, _range_local) in range.iter(){
+                self.unsafe_write_block(*block_id, &zeroes, write_through).await?;
+            }
+            Ok(())
+        }
+    }
+
+    async unsafe fn unsafe_block_status(&mut self, offset: usize, size: usize) -> std::io::Result<Vec<Extent>> {
+        self.load_allocated_blocks().await?;
+        let allocated_blocks=self.allocated_blocks.as_ref().unwrap();
+        let mut extents=Vec::new();
+        for (block_id, _range_block, range_local) in self.block_range(offset, size).iter(){
+            let length=range_local.end-range_local.start;
+            if allocated_blocks.contains(block_id){
+                push_extent(&mut extents, Extent::data(length));
+            }else{
+                // Missing blocks read as zeroes, see get_block.
+                push_extent(&mut extents, Extent::hole(length));
+            }
+        }
+        Ok(extents)
+    }
+
+    async fn resize(&mut self, size: usize) -> std::io::Result<()> {
+        if size==0 || size % BLOCK_SIZE !=0{
+            return Err(ErrorKind::InvalidInput)?;
+        }
+        if size<self.total_size{
+            // Drop the blocks past the new end, so that growing again brings back zeroes.
+            self.load_allocated_blocks().await?;
+            let truncated:Vec<usize>=self.allocated_blocks.as_ref().unwrap().range(size/BLOCK_SIZE..).cloned().collect();
+            for block_id in truncated{
+                self.delete_block_with_retries(block_id).await?;
+            }
+        }
+        println!("Resize {} -> {}", self.total_size, size);
+        self.total_size=size;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities{trim: true, fua: true, rotational: false, resize: true, read_only: false, shared_read: true}
+    }
+
     async fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
@@ -206,4 +339,27 @@ impl CloudProvider for SeafileProvider {
     fn block_size(&self) -> usize {
         BLOCK_SIZE
     }
-}
\ No newline at end of file
+}
+struct SeafileFactory;
+#[async_trait]
+impl BackendFactory for SeafileFactory{
+    async fn create(&self, spec: &ProviderSpec, size: Option<usize>) -> std::result::Result<Box<dyn CloudProvider>, Box<dyn std::error::Error>> {
+        let location=spec.location()?;
+        let api_base=match location.rfind('/'){
+            Some(index)=>format!("https://{}/api2/", &location[..index]),
+            None=>String::from(SEAFILE_API_BASE)
+        };
+        let library=&location[location.rfind('/').map(|index| index+1).unwrap_or(0)..];
+        let token=match spec.arg::<String>("token")?{
+            Some(token)=>token,
+            None=>std::env::var("SEAFILE_TOKEN").map_err(|_| RegistryError::MissingArgument(String::from("seafile.token")))?
+        };
+        let size=spec.size(size)?;
+        Ok(Box::new(SeafileProvider::connect_to(&api_base, &token, library, size).await?))
+    }
+}
+/// Registers `seafile(token=...)://server/library`. Without a server the default one is used,
+/// and without a token it is taken from SEAFILE_TOKEN.
+pub fn register(registry: &mut Registry){
+    registry.register_backend("seafile", SeafileFactory);
+}
@@ -0,0 +1,474 @@
+use reqwest::*;
+use async_trait::async_trait;
+use std::ops::Range;
+use crate::support::{Capabilities, CloudProvider, CloudProviderExt, Extent, copy_padded, push_extent};
+use crate::support::registry::{BackendFactory, ProviderSpec, Registry};
+use crate::support::retry::{retry, transient_status, Transient};
+use std::io::ErrorKind;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Size of the files blocks are stored in, unless configured otherwise.
+pub const DEFAULT_BLOCK_SIZE:usize=64*1024;
+
+/// How requests to the WebDAV server are authenticated.
+#[derive(Debug, Clone)]
+pub enum WebDavAuth{
+    None,
+    Basic{username: String, password: String},
+    Bearer(String)
+}
+impl WebDavAuth{
+    /// Picks the authentication from the given credentials, each falling back to WEBDAV_USER, WEBDAV_PASSWORD
+    /// and WEBDAV_TOKEN. A user name means basic authentication, otherwise a token means a bearer token.
+    pub fn resolve(username: Option<String>, password: Option<String>, token: Option<String>)->Self{
+        let username=username.or_else(|| std::env::var("WEBDAV_USER").ok());
+        let password=password.or_else(|| std::env::var("WEBDAV_PASSWORD").ok());
+        let token=token.or_else(|| std::env::var("WEBDAV_TOKEN").ok());
+        match (username, token){
+            (Some(username), _)=>WebDavAuth::Basic{username, password: password.unwrap_or_default()},
+            (None, Some(token))=>WebDavAuth::Bearer(token),
+            (None, None)=>WebDavAuth::None
+        }
+    }
+}
+/// Stores every block as a file `{id}.block` in a WebDAV collection, e.g. a folder on Nextcloud,
+/// ownCloud or Apache mod_dav. With a fan-out, blocks are spread over nested collections
+/// `{id / fan_out}/{id}.block`, since many servers slow down on large collections.
+pub struct WebDavProvider {
+    total_size: usize,
+    block_size: usize,
+    http: Client,
+    /// URL of the collection holding the volume, ending with `/`.
+    base_url: String,
+    auth: WebDavAuth,
+    fan_out: Option<usize>,
+    /// Nested collections known to exist.
+    collections: BTreeSet<usize>,
+    /// Blocks known to exist on the server, loaded on the first allocation query.
+    allocated_blocks: Option<BTreeSet<usize>>
+}
+#[derive(Debug)]
+pub enum WebDavError{
+    Auth,
+    NoCollection,
+    Io(Box<dyn std::error::Error+Send+Sync>),
+    BadTotalSize,
+    BadResponse(u16)
+}
+impl fmt::Display for WebDavError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self{
+            WebDavError::Auth=>write!(f, "WebDAV credentials were refused"),
+            WebDavError::NoCollection=>write!(f, "WebDAV collection not found and could not be created"),
+            WebDavError::Io(err)=>write!(f, "WebDAV request failed: {}", err),
+            WebDavError::BadTotalSize=>write!(f, "WebDAV export size must be a positive multiple of the block size, and fan_out positive"),
+            WebDavError::BadResponse(status)=>write!(f, "WebDAV request failed with status {}", status)
+        }
+    }
+}
+impl std::error::Error for WebDavError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // Generic error, underlying cause isn't tracked.
+        None
+    }
+}
+impl From<WebDavError> for std::io::Error{
+    fn from(e: WebDavError) -> Self {
+        std::io::Error::new(ErrorKind::Other, e)
+    }
+}
+impl From<reqwest::Error> for WebDavError{
+    fn from(e: reqwest::Error) -> Self {
+        WebDavError::Io(Box::new(e))
+    }
+}
+impl Transient for WebDavError{
+    fn is_transient(&self)->bool{
+        match self{
+            WebDavError::Io(_)=>true,
+            WebDavError::BadResponse(status)=>transient_status(*status),
+            _=>false
+        }
+    }
+}
+pub type Result<T>=std::result::Result<T, WebDavError>;
+
+const PROPFIND_BODY:&str=r#"<?xml version="1.0" encoding="utf-8"?><propfind xmlns="DAV:"><prop><resourcetype/></prop></propfind>"#;
+
+/// The text of every `href` element in a multistatus response, whatever namespace prefix the server uses.
+fn hrefs(xml: &str)->Vec<&str>{
+    let mut hrefs=Vec::new();
+    let mut rest=xml;
+    while let Some(start)=rest.find('<'){
+        rest=&rest[start+1..];
+        let end=match rest.find('>'){
+            Some(end)=>end,
+            None=>break
+        };
+        let tag=&rest[..end];
+        rest=&rest[end+1..];
+        let name=tag.split_whitespace().next().unwrap_or("");
+        if !name.starts_with('/') && name.rsplit(':').next()==Some("href"){
+            hrefs.push(rest[..rest.find('<').unwrap_or(rest.len())].trim());
+        }
+    }
+    hrefs
+}
+/// The number a listed file or collection is named after, e.g. 12 for `.../12.block` or `.../12/`.
+fn href_number(href: &str, suffix: &str)->Option<usize>{
+    href.trim_end_matches('/').rsplit('/').next()?.strip_suffix(suffix)?.parse::<usize>().ok()
+}
+
+impl WebDavProvider{
+    /// Connects to a collection, creating it if missing. `url` should end with `/`.
+    pub async fn connect(url: &str, auth: WebDavAuth, total_size: usize, block_size: usize, fan_out: Option<usize>)->Result<Self>{
+        if block_size==0 || total_size==0 || total_size % block_size !=0 || fan_out==Some(0){
+            return Err(WebDavError::BadTotalSize);
+        }
+        let mut client_builder=ClientBuilder::new().user_agent("CloudDrive WebDAV Provider").tcp_nodelay();
+        if let Ok(url)=std::env::var("https_proxy"){
+            client_builder=client_builder.proxy(Proxy::https(&url).unwrap());
+        }
+        if let Ok(url)=std::env::var("http_proxy"){
+            client_builder=client_builder.proxy(Proxy::http(&url).unwrap());
+        }
+        let mut base_url=String::from(url);
+        if !base_url.ends_with('/'){
+            base_url.push('/');
+        }
+        let webdav=WebDavProvider{
+            total_size,
+            block_size,
+            http: client_builder.build().unwrap(),
+            base_url,
+            auth,
+            fan_out,
+            collections: BTreeSet::new(),
+            allocated_blocks: None
+        };
+        match webdav.propfind(&webdav.base_url, 0).await?.status(){
+            StatusCode::MULTI_STATUS | StatusCode::OK=>Ok(webdav),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN=>Err(WebDavError::Auth),
+            StatusCode::NOT_FOUND=>{
+                webdav.mkcol(&webdav.base_url).await.map_err(|_| WebDavError::NoCollection)?;
+                Ok(webdav)
+            }
+            status=>Err(WebDavError::BadResponse(status.as_u16()))
+        }
+    }
+    fn request(&self, method: Method, url: &str)->RequestBuilder{
+        let request=self.http.request(method, url);
+        match &self.auth{
+            WebDavAuth::None=>request,
+            WebDavAuth::Basic{username, password}=>request.basic_auth(username, Some(password)),
+            WebDavAuth::Bearer(token)=>request.bearer_auth(token)
+        }
+    }
+    fn collection_url(&self, collection: usize)->String{
+        format!("{}{}/", self.base_url, collection)
+    }
+    fn block_url(&self, block_id: usize)->String{
+        match self.fan_out{
+            Some(fan_out)=>format!("{}{}.block", self.collection_url(block_id/fan_out), block_id),
+            None=>format!("{}{}.block", self.base_url, block_id)
+        }
+    }
+    async fn propfind(&self, url: &str, depth: usize)->Result<Response>{
+        Ok(self.request(Method::from_bytes(b"PROPFIND").unwrap(), url)
+            .header("Depth", depth.to_string())
+            .header(header::CONTENT_TYPE, "application/xml")
+            .body(PROPFIND_BODY)
+            .send().await?)
+    }
+    async fn mkcol(&self, url: &str)->Result<()>{
+        let response=self.request(Method::from_bytes(b"MKCOL").unwrap(), url).send().await?;
+        // 405 means the collection exists already.
+        if response.status().is_success() || response.status()==StatusCode::METHOD_NOT_ALLOWED{
+            Ok(())
+        }else{
+            Err(WebDavError::BadResponse(response.status().as_u16()))
+        }
+    }
+    /// Fetches a block, which comes back empty if it does not exist.
+    async fn get_block(&self, block_id: usize)->Result<bytes::Bytes>{
+        let response=self.request(Method::GET, &self.block_url(block_id)).send().await?;
+        match response.status(){
+            StatusCode::OK=>Ok(response.bytes().await?),
+            // considered as uninitialized chunks, which read as zeroes.
+            StatusCode::NOT_FOUND=>Ok(bytes::Bytes::new()),
+            status=>Err(WebDavError::BadResponse(status.as_u16()))
+        }
+    }
+    async fn put_block(&self, block_id: usize, buf: &[u8])->Result<()>{
+        let response=self.request(Method::PUT, &self.block_url(block_id)).body(buf.to_vec()).send().await?;
+        match response.status(){
+            status if status.is_success()=>Ok(()),
+            StatusCode::CONFLICT=>{
+                // The parent collection was removed behind our back, so create it again before the next attempt.
+                if let Some(fan_out)=self.fan_out{
+                    self.mkcol(&self.collection_url(block_id/fan_out)).await?;
+                }
+                Err(WebDavError::BadResponse(StatusCode::CONFLICT.as_u16()))
+            }
+            status=>Err(WebDavError::BadResponse(status.as_u16()))
+        }
+    }
+    async fn delete_block(&self, block_id: usize)->Result<()>{
+        let response=self.request(Method::DELETE, &self.block_url(block_id)).send().await?;
+        // A missing block is as good as a deleted one.
+        if response.status().is_success() || response.status()==StatusCode::NOT_FOUND{
+            Ok(())
+        }else{
+            Err(WebDavError::BadResponse(response.status().as_u16()))
+        }
+    }
+    /// Lists a collection with PROPFIND, returning the hrefs of its members.
+    async fn list_collection(&self, url: &str)->Result<Vec<String>>{
+        let response=self.propfind(url, 1).await?;
+        match response.status(){
+            StatusCode::MULTI_STATUS=>Ok(hrefs(&response.text().await?).into_iter().map(String::from).collect()),
+            StatusCode::NOT_FOUND=>Ok(Vec::new()),
+            status=>Err(WebDavError::BadResponse(status.as_u16()))
+        }
+    }
+    async fn list_collection_with_retries(&self, url: &str)->Result<Vec<String>>{
+        retry("WebDAV list_collection", || self.list_collection(url)).await
+    }
+    async fn list_blocks(&mut self)->Result<BTreeSet<usize>>{
+        let members=self.list_collection_with_retries(&self.base_url).await?;
+        if self.fan_out.is_none(){
+            return Ok(members.iter().filter_map(|href| href_number(href, ".block")).collect());
+        }
+        // The collection itself is listed as well.
+        let base_url=self.base_url.trim_end_matches('/');
+        let collections: BTreeSet<usize>=members.iter()
+            .filter(|href| href.ends_with('/') && !base_url.ends_with(href.trim_end_matches('/')))
+            .filter_map(|href| href_number(href, ""))
+            .collect();
+        let mut blocks=BTreeSet::new();
+        for collection in collections.iter(){
+            let members=self.list_collection_with_retries(&self.collection_url(*collection)).await?;
+            blocks.extend(members.iter().filter_map(|href| href_number(href, ".block")));
+        }
+        self.collections=collections;
+        Ok(blocks)
+    }
+    async fn load_allocated_blocks(&mut self)->Result<()>{
+        if self.allocated_blocks.is_none(){
+            self.allocated_blocks=Some(self.list_blocks().await?);
+        }
+        Ok(())
+    }
+    async fn put_block_with_retries(&mut self, block_id: usize, buf: &[u8])->Result<()>{
+        if let Some(fan_out)=self.fan_out{
+            let collection=block_id/fan_out;
+            if !self.collections.contains(&collection){
+                let url=self.collection_url(collection);
+                retry("WebDAV mkcol", || self.mkcol(&url)).await?;
+                self.collections.insert(collection);
+            }
+        }
+        let this=&*self;
+        retry("WebDAV put_block", || this.put_block(block_id, buf)).await?;
+        if let Some(blocks)=&mut self.allocated_blocks{
+            blocks.insert(block_id);
+        }
+        Ok(())
+    }
+    async fn get_block_with_retries(&self, block_id: usize, buf: &mut [u8])->Result<()>{
+        let bytes=retry("WebDAV get_block", || self.get_block(block_id)).await?;
+        copy_padded(buf, &bytes);
+        Ok(())
+    }
+    async fn delete_block_with_retries(&mut self, block_id: usize)->Result<()>{
+        let this=&*self;
+        retry("WebDAV delete_block", || this.delete_block(block_id)).await?;
+        if let Some(blocks)=&mut self.allocated_blocks{
+            blocks.remove(&block_id);
+        }
+        Ok(())
+    }
+}
+#[async_trait]
+impl CloudProvider for WebDavProvider {
+    fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    async unsafe fn unsafe_write(&mut self, offset: usize, buf: &[u8], _write_through: bool) -> std::io::Result<()> {
+        let range=self.block_range(offset, buf.len());
+        for (block_id, range_block, range_local) in range.iter(){
+            if range_block.len()==self.block_size{
+                self.put_block_with_retries(*block_id, &buf[Range::clone(range_local)]).await?;
+            }else{
+                // Files can only be replaced as a whole.
+                let mut block=self.create_block_buffer();
+                self.get_block_with_retries(*block_id, &mut block).await?;
+                block[Range::clone(range_block)].copy_from_slice(&buf[Range::clone(range_local)]);
+                self.put_block_with_retries(*block_id, &block).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_read(&mut self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        self.unsafe_read_shared(offset, buf).await
+    }
+
+    async unsafe fn unsafe_read_shared(&self, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        let range=self.block_range(offset, buf.len());
+        for (block_id, range_block, range_local) in range.iter(){
+            if range_block.len()==self.block_size{
+                self.get_block_with_retries(*block_id, &mut buf[Range::clone(range_local)]).await?;
+            }else{
+                let mut block=self.create_block_buffer();
+                self.get_block_with_retries(*block_id, &mut block).await?;
+                buf[Range::clone(range_local)].copy_from_slice(&block[Range::clone(range_block)]);
+            }
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_discard(&mut self, offset: usize, size: usize) -> std::io::Result<()> {
+        let range=self.block_range(offset, size);
+        for (block_id, range_block, _range_local) in range.iter(){
+            // Partially discarded blocks are kept, as discarding is only a hint.
+            if range_block.len()==self.block_size{
+                self.delete_block_with_retries(*block_id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async unsafe fn unsafe_write_zeroes(&mut self, offset: usize, size: usize, may_trim: bool, fast_only: bool, write_through: bool) -> std::io::Result<()> {
+        let aligned=offset % self.block_size==0 && size % self.block_size==0;
+        if may_trim && aligned{
+            // Missing blocks read as zeroes.
+            self.unsafe_discard(offset, size).await
+        }else if fast_only{
+            Err(ErrorKind::Unsupported)?
+        }else{
+            let zeroes=vec![0; self.block_size];
+            for (_block_id, range_block, range_local) in self.block_range(offset, size).iter(){
+                self.unsafe_write(offset+range_local.start, &zeroes[Range::clone(range_block)], write_through).await?;
+            }
+            Ok(())
+        }
+    }
+
+    async unsafe fn unsafe_block_status(&mut self, offset: usize, size: usize) -> std::io::Result<Vec<Extent>> {
+        self.load_allocated_blocks().await?;
+        let allocated_blocks=self.allocated_blocks.as_ref().unwrap();
+        let mut extents=Vec::new();
+        for (block_id, _range_block, range_local) in self.block_range(offset, size).iter(){
+            let length=range_local.end-range_local.start;
+            if allocated_blocks.contains(block_id){
+                push_extent(&mut extents, Extent::data(length));
+            }else{
+                // Missing blocks read as zeroes, see get_block.
+                push_extent(&mut extents, Extent::hole(length));
+            }
+        }
+        Ok(extents)
+    }
+
+    async fn resize(&mut self, size: usize) -> std::io::Result<()> {
+        if size==0 || size % self.block_size !=0{
+            return Err(ErrorKind::InvalidInput)?;
+        }
+        if size<self.total_size{
+            // Drop the blocks past the new end, so that growing again brings back zeroes.
+            self.load_allocated_blocks().await?;
+            let truncated:Vec<usize>=self.allocated_blocks.as_ref().unwrap().range(size/self.block_size..).cloned().collect();
+            for block_id in truncated{
+                self.delete_block_with_retries(block_id).await?;
+            }
+        }
+        self.total_size=size;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities{trim: true, fua: true, rotational: false, resize: true, read_only: false, shared_read: true}
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
+struct WebDavFactory;
+#[async_trait]
+impl BackendFactory for WebDavFactory{
+    async fn create(&self, spec: &ProviderSpec, size: Option<usize>) -> std::result::Result<Box<dyn CloudProvider>, Box<dyn std::error::Error>> {
+        let scheme=spec.arg::<String>("scheme")?.unwrap_or_else(|| String::from("https"));
+        let url=format!("{}://{}", scheme, spec.location()?);
+        let auth=WebDavAuth::resolve(spec.arg("user")?, spec.arg("password")?, spec.arg("token")?);
+        let block_size=if spec.args.contains_key("block") {spec.size_arg("block")?} else {DEFAULT_BLOCK_SIZE};
+        let size=spec.size(size)?;
+        Ok(Box::new(WebDavProvider::connect(&url, auth, size, block_size, spec.arg("fanout")?).await?))
+    }
+}
+/// Registers `webdav(user=..., password=..., block=64K, fanout=1024)://server/remote.php/dav/files/user/volume/`.
+/// `token` gives a bearer token instead, credentials fall back to WEBDAV_USER, WEBDAV_PASSWORD and WEBDAV_TOKEN,
+/// and `scheme=http` talks to servers without TLS.
+pub fn register(registry: &mut Registry){
+    registry.register_backend("webdav", WebDavFactory);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const MULTISTATUS: &str=r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response><d:href>/remote.php/dav/files/user/volume/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat></d:response>
+  <d:response><d:href>/remote.php/dav/files/user/volume/12.block</d:href><d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat></d:response>
+  <d:response><d:href>
+    /remote.php/dav/files/user/volume/3/
+  </d:href></d:response>
+</d:multistatus>"#;
+
+    #[test]
+    fn finds_hrefs(){
+        assert_eq!(hrefs(MULTISTATUS), ["/remote.php/dav/files/user/volume/", "/remote.php/dav/files/user/volume/12.block", "/remote.php/dav/files/user/volume/3/"]);
+        // Servers pick their own prefix, or none at all.
+        assert_eq!(hrefs(r#"<D:multistatus xmlns:D="DAV:"><D:response><D:href>/a/1.block</D:href></D:response></D:multistatus>"#), ["/a/1.block"]);
+        assert_eq!(hrefs(r#"<multistatus xmlns="DAV:"><response><href>/a/1.block</href></response></multistatus>"#), ["/a/1.block"]);
+        assert!(hrefs("<d:multistatus><d:hrefs>x</d:hrefs></d:multistatus>").is_empty());
+        assert!(hrefs("").is_empty());
+        assert_eq!(hrefs("<d:href>/a/1.block"), ["/a/1.block"]);
+    }
+
+    #[test]
+    fn numbers_members(){
+        assert_eq!(href_number("/volume/12.block", ".block"), Some(12));
+        assert_eq!(href_number("https://server/volume/0.block", ".block"), Some(0));
+        assert_eq!(href_number("/volume/3/", ""), Some(3));
+        assert_eq!(href_number("/volume/3", ""), Some(3));
+        assert_eq!(href_number("/volume/12.block.tmp", ".block"), None);
+        assert_eq!(href_number("/volume/x.block", ".block"), None);
+        assert_eq!(href_number("/volume/", ""), None);
+        assert_eq!(href_number("/volume/12.block", ""), None);
+    }
+
+    #[test]
+    fn prefers_basic_authentication(){
+        match WebDavAuth::resolve(Some(String::from("user")), None, Some(String::from("token"))){
+            WebDavAuth::Basic{username, password}=>assert_eq!((username.as_str(), password.is_empty()), ("user", std::env::var("WEBDAV_PASSWORD").is_err())),
+            auth=>panic!("{:?}", auth)
+        }
+    }
+
+    #[test]
+    fn retries_only_transient_errors(){
+        assert!(WebDavError::BadResponse(503).is_transient());
+        assert!(WebDavError::BadResponse(409).is_transient());
+        assert!(!WebDavError::BadResponse(403).is_transient());
+        assert!(!WebDavError::Auth.is_transient());
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::fmt;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{ServerConfig, NoClientAuth, AllowAnyAuthenticatedClient, RootCertStore};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+
+#[derive(Debug)]
+pub enum TlsError{
+    BadCertificate,
+    BadPrivateKey,
+    BadClientCA,
+    Io(std::io::Error),
+    Config(tokio_rustls::rustls::TLSError)
+}
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self{
+            TlsError::BadCertificate=>write!(f, "No usable certificate in the TLS certificate file"),
+            TlsError::BadPrivateKey=>write!(f, "No usable PKCS#8 or RSA private key in the TLS key file"),
+            TlsError::BadClientCA=>write!(f, "No usable certificate in the TLS client CA file"),
+            TlsError::Io(err)=>write!(f, "Failed to read TLS files: {}", err),
+            TlsError::Config(err)=>write!(f, "Bad TLS configuration: {}", err)
+        }
+    }
+}
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // Generic error, underlying cause isn't tracked.
+        None
+    }
+}
+impl From<std::io::Error> for TlsError{
+    fn from(e: std::io::Error) -> Self {
+        TlsError::Io(e)
+    }
+}
+pub type Result<T>=std::result::Result<T, TlsError>;
+
+/// Builds the acceptor used for NBD_OPT_STARTTLS from PEM files.
+/// When `client_ca` is given, clients must present a certificate signed by it.
+pub fn load_acceptor(cert: &str, key: &str, client_ca: Option<&str>)->Result<TlsAcceptor>{
+    let cert_chain=certs(&mut BufReader::new(File::open(cert)?)).map_err(|_| TlsError::BadCertificate)?;
+    if cert_chain.is_empty(){
+        return Err(TlsError::BadCertificate);
+    }
+    let mut keys=pkcs8_private_keys(&mut BufReader::new(File::open(key)?)).map_err(|_| TlsError::BadPrivateKey)?;
+    if keys.is_empty(){
+        keys=rsa_private_keys(&mut BufReader::new(File::open(key)?)).map_err(|_| TlsError::BadPrivateKey)?;
+    }
+    if keys.is_empty(){
+        return Err(TlsError::BadPrivateKey);
+    }
+    let mut config=match client_ca{
+        Some(client_ca)=>{
+            let mut roots=RootCertStore::empty();
+            let (valid, _invalid)=roots.add_pem_file(&mut BufReader::new(File::open(client_ca)?)).map_err(|_| TlsError::BadClientCA)?;
+            if valid==0{
+                return Err(TlsError::BadClientCA);
+            }
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None=>ServerConfig::new(NoClientAuth::new())
+    };
+    config.set_single_cert(cert_chain, keys.remove(0)).map_err(TlsError::Config)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}